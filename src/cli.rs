@@ -5,6 +5,7 @@ use std::{
 };
 
 use clap::{Parser, Subcommand};
+use serde::{Deserialize, Serialize};
 
 pub(crate) enum Source {
     Arg(String),
@@ -19,6 +20,17 @@ pub(crate) enum Commands {
     Connect(ArgsConnect),
     Query(ArgsQuery),
     Execute(ArgsExecute),
+    /// List the names of open background connections
+    List,
+    /// Show server, database and idle time of open background connections
+    Status(ArgsStatus),
+    /// Close open background connections
+    Close(ArgsClose),
+    /// Begin, commit or roll back a transaction on an open background connection
+    Tx(ArgsTx),
+    /// Background process holding connections opened with `connect -d`
+    #[command(hide = true)]
+    Daemon,
 }
 
 #[derive(clap::Parser, Clone, Debug)]
@@ -34,9 +46,45 @@ pub(crate) struct ArgsConnect {
     pub connection_string: Option<String>,
     #[arg(short, long)]
     pub name: Option<String>,
+    /// Keep the connection open in the background for later commands
+    #[arg(short, long)]
+    pub detach: bool,
 }
 
-#[derive(clap::Parser, Clone, Debug)]
+#[derive(clap::Args, Clone, Debug)]
+pub(crate) struct ArgsStatus {
+    /// Only show this connection
+    #[arg(short, long)]
+    pub name: Option<String>,
+}
+
+#[derive(clap::Args, Clone, Debug)]
+pub(crate) struct ArgsClose {
+    #[arg(short, long)]
+    pub name: Option<String>,
+    /// Close every open connection
+    #[arg(short, long, conflicts_with = "name")]
+    pub all: bool,
+}
+
+#[derive(clap::Args, Clone, Debug)]
+pub(crate) struct ArgsTx {
+    pub action: TxAction,
+    #[arg(short, long)]
+    pub name: Option<String>,
+    /// Roll back an open transaction after this many seconds without activity
+    #[arg(long, default_value_t = 300)]
+    pub idle_timeout: u64,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Serialize, Deserialize)]
+pub(crate) enum TxAction {
+    Begin,
+    Commit,
+    Rollback,
+}
+
+#[derive(clap::Parser, Clone, Debug, Serialize, Deserialize)]
 pub(crate) struct ArgsQuery {
     #[arg(short, long)]
     pub connection_string: Option<String>,
@@ -44,25 +92,211 @@ pub(crate) struct ArgsQuery {
     pub name: Option<String>,
     #[arg(short, long)]
     pub query: Option<String>,
+    /// Value for a `$name` placeholder in the query, or a file of them
+    #[arg(short, long = "param", value_name = "NAME=VALUE")]
+    pub param: Vec<String>,
+    /// Format of parameter files, by default told by their extension
+    #[arg(long, value_name = "FORMAT")]
+    pub param_format: Option<ParamFormat>,
+    /// Format of the results, by default told by the extension of the `-o`
+    /// file or else CSV
     #[arg(short, long)]
     pub format: Option<OutputFormat>,
+    /// Write the results to this file instead of stdout, replacing it only
+    /// once the query has run to the end
+    #[arg(short, long, value_name = "PATH")]
+    pub output: Option<String>,
+    /// Table to insert rows into with `-f sql`, by default named after the
+    /// `-o` file
+    #[arg(long = "table", value_name = "NAME")]
+    pub insert_into: Option<String>,
+    /// Encoding of binary values
+    #[arg(long, value_enum, value_name = "ENCODING", default_value_t = Binary::Hex)]
+    pub binary: Binary,
+    /// Write server messages, such as `PRINT` output, to this file instead
+    /// of stderr
+    #[arg(long, value_name = "PATH")]
+    pub messages: Option<String>,
+    /// Format of server messages
+    #[arg(long, value_enum, value_name = "FORMAT", default_value_t = MessageFormat::Text)]
+    pub messages_format: MessageFormat,
+    #[command(flatten, next_help_heading = "CSV output")]
+    pub csv: CsvOptions,
+    #[command(flatten, next_help_heading = "JSON output")]
+    pub json: JsonOptions,
+    #[command(flatten, next_help_heading = "Table output")]
+    pub table: TableOptions,
 }
 
-#[derive(clap::ValueEnum, Clone, Debug, Default)]
+#[derive(clap::ValueEnum, Clone, Debug, Default, Serialize, Deserialize)]
 pub(crate) enum OutputFormat {
     #[default]
+    Csv,
+    /// An array of objects per result set
     Json,
+    /// One object per row and line, written as soon as the row arrives
+    #[value(alias = "jsonl")]
+    Ndjson,
+    /// Aligned table drawn with box characters
     Text,
+    /// GitHub flavored markdown table
+    #[value(alias = "md")]
+    Markdown,
+    /// An `INSERT` statement for each row
+    Sql,
 }
 
-#[derive(clap::Parser, Clone, Debug)]
+#[derive(clap::Args, Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct CsvOptions {
+    /// Character between fields, `\t` for a tab
+    #[arg(long, value_name = "CHAR", default_value = ",")]
+    pub delimiter: String,
+    /// Character fields are quoted with when they need to be
+    #[arg(long, value_name = "CHAR", default_value = "\"")]
+    pub quote: String,
+    #[arg(long, value_enum, default_value_t = LineEnding::Lf)]
+    pub line_ending: LineEnding,
+    /// Text written for NULL
+    #[arg(long, value_name = "TEXT", default_value = "")]
+    pub null: String,
+    /// Start with a UTF-8 byte order mark, for Excel
+    #[arg(long)]
+    pub bom: bool,
+    /// Leave out the row of column names
+    #[arg(long)]
+    pub no_header: bool,
+}
+
+#[derive(clap::Args, Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct JsonOptions {
+    /// Write `-f json` as one document holding every result set with its
+    /// columns, the rows affected, server messages and the time taken
+    #[arg(long)]
+    pub json_envelope: bool,
+    /// Start each result set of `-f ndjson` with a line naming its columns
+    #[arg(long)]
+    pub set_markers: bool,
+    /// How values JSON has no exact type for are written
+    #[arg(long, value_enum, value_name = "POLICY", default_value_t = JsonTypes::Native)]
+    pub json_types: JsonTypes,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum JsonTypes {
+    /// Numbers as numbers, decimals, dates and binary as strings
+    #[default]
+    Native,
+    /// As native, but integers beyond 2^53 as strings, which JavaScript
+    /// would round
+    Safe,
+    /// Decimals, bigints, binary, dates and the like as objects naming
+    /// their type, e.g. `{"$decimal":"1.10"}`
+    Tagged,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum Binary {
+    /// Two hex digits per byte
+    #[default]
+    Hex,
+    Base64,
+    /// Hex as a T-SQL literal, e.g. `0x0AFF`
+    #[value(name = "0x")]
+    Prefixed,
+    /// Left out, as an empty cell or a missing JSON key
+    Omit,
+}
+
+#[derive(clap::Args, Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct TableOptions {
+    /// Cut text longer than this many columns short, ending it in `...`
+    #[arg(
+        long,
+        value_name = "COLUMNS",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(3..)
+    )]
+    pub max_width: Option<usize>,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Serialize, Deserialize)]
+pub(crate) enum LineEnding {
+    Lf,
+    Crlf,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Serialize, Deserialize)]
+pub(crate) enum ParamFormat {
+    Toml,
+    Json,
+    Csv,
+    #[value(alias = "md")]
+    Markdown,
+    /// One JSON object per line
+    #[value(alias = "jsonl")]
+    Ndjson,
+}
+
+#[derive(clap::Parser, Clone, Debug, Serialize, Deserialize)]
 pub(crate) struct ArgsExecute {
     #[arg(short, long)]
     pub connection_string: Option<String>,
     #[arg(short, long)]
     pub name: Option<String>,
-    #[arg(short, long)]
+    #[arg(short = 'q', long)]
     pub script: Option<String>,
+    /// Value for a `$name` placeholder in the script, or a file of them
+    #[arg(short, long = "param", value_name = "NAME=VALUE")]
+    pub param: Vec<String>,
+    /// Format of parameter files, by default told by their extension
+    #[arg(long, value_name = "FORMAT")]
+    pub param_format: Option<ParamFormat>,
+    /// Run the script once for each row of the parameter file
+    #[arg(short, long)]
+    pub stream: bool,
+    /// Send up to this many parameter rows to the server at once
+    #[arg(short, long, requires = "stream", value_name = "ROWS")]
+    pub batch_size: Option<usize>,
+    /// Write the script with its parameters filled in to this file, `-` for
+    /// stdout, instead of running it
+    #[arg(short, long, value_name = "PATH")]
+    pub output: Option<String>,
+    #[arg(short, long)]
+    pub format: Option<ScriptFormat>,
+    /// Whether a failing batch stops the script, `:on error` in the script
+    /// takes precedence
+    #[arg(long, value_enum, default_value_t = OnError::Stop)]
+    pub on_error: OnError,
+    /// Write server messages, such as `PRINT` output, to this file instead
+    /// of stderr
+    #[arg(long, value_name = "PATH")]
+    pub messages: Option<String>,
+    /// Format of server messages
+    #[arg(long, value_enum, value_name = "FORMAT", default_value_t = MessageFormat::Text)]
+    pub messages_format: MessageFormat,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub(crate) enum MessageFormat {
+    /// One message per line
+    #[default]
+    Text,
+    /// One JSON object per line, holding the message
+    Json,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum OnError {
+    /// Stop at the first failing batch
+    #[default]
+    Stop,
+    /// Run the remaining batches and report every failure at the end
+    Continue,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Serialize, Deserialize)]
+pub(crate) enum ScriptFormat {
+    /// T-SQL with parameters written as literals
+    Sql,
 }
 
 impl Source {
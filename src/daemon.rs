@@ -0,0 +1,689 @@
+use core::fmt;
+use std::{
+    collections::{btree_map::Entry, BTreeMap},
+    fs::{DirBuilder, Permissions},
+    io::{self, BufReader, BufWriter, Write},
+    os::unix::{
+        fs::{DirBuilderExt, MetadataExt, PermissionsExt},
+        net::{UnixListener, UnixStream},
+        process::CommandExt,
+    },
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError, TryLockError,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Context};
+use async_std::task::block_on;
+
+use crate::{
+    cli, execute,
+    fmt_util::{Format, Join},
+    messages::Messages,
+    mssql, output, params,
+};
+
+type Result<T> = std::result::Result<T, anyhow::Error>;
+
+pub(crate) const DEFAULT_CONNECTION: &str = "default";
+
+/// Path of the socket the daemon listens on, `DB_SOCKET` overrides the default.
+pub(crate) fn socket_path() -> PathBuf {
+    match std::env::var_os("DB_SOCKET") {
+        Some(path) => path.into(),
+        None => default_socket_dir().join("db.sock"),
+    }
+}
+
+/// Directory holding the default socket, private to the user so that no one
+/// else can plant or reach a socket in it.
+fn default_socket_dir() -> PathBuf {
+    let dir = std::env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(std::env::temp_dir);
+    let user = std::env::var("USER").unwrap_or_default();
+    dir.join(format!("db-{user}"))
+}
+
+/// Creates `dir` with access for this user only, refusing an existing one
+/// that belongs to or is open to anyone else.
+fn create_private_dir(dir: &Path) -> Result<()> {
+    match DirBuilder::new().mode(0o700).create(dir) {
+        Ok(()) => return Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+        Err(e) => return Err(e).with_context(|| format!("failed to create `{}`", dir.display())),
+    }
+    let meta = std::fs::symlink_metadata(dir)?;
+    if !meta.is_dir() || meta.uid() != euid() {
+        bail!("`{}` is not a directory owned by this user", dir.display());
+    }
+    if meta.mode() & 0o077 != 0 {
+        bail!("`{}` is accessible to other users", dir.display());
+    }
+    Ok(())
+}
+
+fn euid() -> u32 {
+    // SAFETY: geteuid cannot fail and has no preconditions
+    unsafe { libc::geteuid() }
+}
+
+pub(crate) struct Client(UnixStream);
+
+impl Client {
+    /// Connects to the running daemon, `None` if there is none.
+    pub(crate) fn connect() -> Result<Option<Self>> {
+        match UnixStream::connect(socket_path()) {
+            Ok(stream) => Ok(Some(Self(stream))),
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused
+                ) =>
+            {
+                Ok(None)
+            }
+            Err(e) => Err(e).context("failed to connect to daemon"),
+        }
+    }
+
+    /// Connects to the daemon, starting it in the background if it is not running.
+    pub(crate) fn connect_or_spawn() -> Result<Self> {
+        if let Some(client) = Self::connect()? {
+            return Ok(client);
+        }
+        Command::new(std::env::current_exe()?)
+            .arg("daemon")
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .process_group(0)
+            .spawn()
+            .context("failed to start daemon")?;
+        for _ in 0..50 {
+            thread::sleep(Duration::from_millis(100));
+            if let Some(client) = Self::connect()? {
+                return Ok(client);
+            }
+        }
+        bail!("timed out waiting for daemon to start")
+    }
+
+    /// Sends `request` and relays its output to `out` until the daemon replies.
+    pub(crate) fn request(self, request: &proto::Request, out: &mut impl Write) -> Result<()> {
+        proto::write_frame(&mut &self.0, proto::REQUEST, &serde_json::to_vec(request)?)?;
+        let mut reader = BufReader::new(&self.0);
+        loop {
+            match proto::read_frame(&mut reader)? {
+                Some((proto::STDOUT, data)) => out.write_all(&data)?,
+                Some((proto::STDERR, data)) => io::stderr().write_all(&data)?,
+                Some((proto::REPLY, data)) => {
+                    out.flush()?;
+                    return match serde_json::from_slice(&data)? {
+                        proto::Reply::Done => Ok(()),
+                        proto::Reply::Error(e) => Err(anyhow!(e)),
+                    };
+                }
+                Some((tag, _)) => bail!("unexpected frame from daemon: {tag}"),
+                None => bail!("daemon closed the connection"),
+            }
+        }
+    }
+}
+
+/// Runs the daemon, serving each client on its own thread. The daemon exits
+/// once it no longer holds any connections.
+pub(crate) fn serve() -> Result<()> {
+    let path = socket_path();
+    if UnixStream::connect(&path).is_ok() {
+        // another daemon is already serving this socket
+        return Ok(());
+    }
+    if std::env::var_os("DB_SOCKET").is_none() {
+        create_private_dir(&default_socket_dir())?;
+    }
+    if let Ok(meta) = std::fs::symlink_metadata(&path) {
+        if meta.uid() != euid() {
+            bail!("`{}` belongs to another user", path.display());
+        }
+        // left behind by a daemon that did not exit cleanly
+        std::fs::remove_file(&path)?;
+    }
+    let listener = UnixListener::bind(&path).context("failed to bind daemon socket")?;
+    // connections are opened with credentials, keep them to this user
+    std::fs::set_permissions(&path, Permissions::from_mode(0o600))?;
+
+    let daemon = Arc::new(Daemon::default());
+    let reaper = daemon.clone();
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(1));
+        reaper.roll_back_idle();
+    });
+    for stream in listener.incoming().flatten() {
+        // counted under the registry lock before the thread starts, so a
+        // request finishing meanwhile cannot shut the daemon down under it
+        let sessions = daemon.sessions();
+        daemon.in_flight.fetch_add(1, Ordering::SeqCst);
+        drop(sessions);
+        let daemon = daemon.clone();
+        thread::spawn(move || daemon.handle(stream));
+    }
+    Ok(())
+}
+
+/// A connection held by the daemon and what it knows about it.
+struct Session {
+    conn: mssql::Connection,
+    server: String,
+    database: String,
+    last_used: Instant,
+    /// How long an open transaction may sit idle before it is rolled back.
+    idle_timeout: Duration,
+    /// The daemon rolled back an idle transaction the client does not know about yet.
+    expired: bool,
+}
+
+impl Session {
+    fn open(connection_string: &str) -> Result<Self> {
+        let mut conn = block_on(mssql::Connection::from_string(connection_string))?;
+        let database = block_on(conn.database())?;
+        Ok(Self {
+            server: conn.server(),
+            database,
+            conn,
+            last_used: Instant::now(),
+            idle_timeout: Duration::MAX,
+            expired: false,
+        })
+    }
+
+    /// Fails while an idle transaction rolled back by the daemon has not been
+    /// acknowledged, so work meant for it does not silently autocommit.
+    fn ensure_not_expired(&self, name: &str) -> Result<()> {
+        if self.expired {
+            bail!(
+                "transaction on `{name}` was rolled back after {}s idle, \
+                 run `db tx rollback -n {name}` to acknowledge",
+                self.idle_timeout.as_secs()
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Captures the server's messages for a client, sending them to its stderr
+/// unless written to a file.
+fn messages(
+    stream: &UnixStream,
+    path: Option<&str>,
+    format: cli::MessageFormat,
+) -> Result<tracing::subscriber::DefaultGuard> {
+    let stderr = proto::FrameWriter::new(stream.try_clone()?, proto::STDERR);
+    Ok(Messages::open(path, format, stderr)?.capture())
+}
+
+/// Registry of open connections keyed by name.
+#[derive(Default)]
+struct Daemon {
+    sessions: Mutex<BTreeMap<String, Arc<Mutex<Session>>>>,
+    in_flight: AtomicUsize,
+}
+
+impl Daemon {
+    fn handle(&self, stream: UnixStream) {
+        let reply = match self.dispatch(&stream) {
+            Ok(()) => proto::Reply::Done,
+            Err(e) => proto::Reply::Error(format!("{e:#}")),
+        };
+
+        // holding the registry lock until exit keeps new requests from starting
+        let shutdown = {
+            let sessions = self.sessions();
+            let last = self.in_flight.fetch_sub(1, Ordering::SeqCst) == 1 && sessions.is_empty();
+            last.then_some(sessions)
+        };
+        if shutdown.is_some() {
+            // unlinked before replying, so the client's next command starts a
+            // new daemon rather than reaching this one as it exits
+            let _ = std::fs::remove_file(socket_path());
+        }
+        // nothing left to do if the client has already gone away
+        let _ = serde_json::to_vec(&reply)
+            .map_err(io::Error::from)
+            .and_then(|data| proto::write_frame(&mut &stream, proto::REPLY, &data));
+        if shutdown.is_some() {
+            std::process::exit(0);
+        }
+    }
+
+    fn dispatch(&self, stream: &UnixStream) -> Result<()> {
+        let request = match proto::read_frame(&mut BufReader::new(stream))? {
+            Some((proto::REQUEST, data)) => serde_json::from_slice(&data)?,
+            _ => bail!("expected a request"),
+        };
+        let mut out = BufWriter::new(proto::FrameWriter::new(stream, proto::STDOUT));
+        match request {
+            proto::Request::Connect {
+                name,
+                connection_string,
+            } => {
+                // replacing it would drop an open transaction unreported
+                let open = |name| anyhow!("connection `{name}` is already open, close it first");
+                if self.sessions().contains_key(&name) {
+                    return Err(open(name));
+                }
+                let session = Session::open(&connection_string)?;
+                // another client may have opened one by that name meanwhile
+                match self.sessions().entry(name) {
+                    Entry::Occupied(entry) => return Err(open(entry.key().clone())),
+                    Entry::Vacant(entry) => {
+                        entry.insert(Arc::new(Mutex::new(session)));
+                    }
+                }
+            }
+            proto::Request::Query(args) => {
+                let name = args.name.as_deref().unwrap_or(DEFAULT_CONNECTION);
+                let session = self.session(name)?;
+                let mut session = session.lock().unwrap_or_else(PoisonError::into_inner);
+                session.ensure_not_expired(name)?;
+                let query_string = args.query.as_deref().unwrap_or_default();
+                let params = params::Params::from_args(&args.param, args.param_format)?;
+                let query_builder = mssql::QueryBuilder::with_params(query_string, &params)?;
+                let _messages = match output::keeps_messages(&args) {
+                    true => None,
+                    false => Some(messages(
+                        stream,
+                        args.messages.as_deref(),
+                        args.messages_format,
+                    )?),
+                };
+                let format = args.format.clone().unwrap_or_default();
+                let res = block_on(async {
+                    let results = query_builder.execute(&mut session.conn).await?;
+                    output::write_results(results, &format, &args, &mut out).await
+                });
+                session.last_used = Instant::now();
+                res?;
+            }
+            proto::Request::Execute { script, args } => {
+                let name = args.name.as_deref().unwrap_or(DEFAULT_CONNECTION);
+                let session = self.session(name)?;
+                let mut session = session.lock().unwrap_or_else(PoisonError::into_inner);
+                session.ensure_not_expired(name)?;
+                let mut err = proto::FrameWriter::new(stream, proto::STDERR);
+                let _messages = messages(stream, args.messages.as_deref(), args.messages_format)?;
+                let res = block_on(execute::run(
+                    &mut session.conn,
+                    &script,
+                    &args,
+                    &mut out,
+                    &mut err,
+                ));
+                session.last_used = Instant::now();
+                res?;
+            }
+            proto::Request::List => {
+                for name in self.sessions().keys() {
+                    writeln!(out, "{name}")?;
+                }
+            }
+            proto::Request::Status { name } => {
+                let sessions: Vec<_> = match name {
+                    Some(name) => vec![(name.clone(), self.session(&name)?)],
+                    None => self.sessions().clone().into_iter().collect(),
+                };
+                write_status(&mut out, sessions)?;
+            }
+            proto::Request::Close { name } => {
+                let closed: Vec<_> = {
+                    let mut sessions = self.sessions();
+                    match name {
+                        Some(name) => {
+                            let session = sessions
+                                .remove(&name)
+                                .ok_or_else(|| anyhow!("no open connection named `{name}`"))?;
+                            vec![(name, session)]
+                        }
+                        None => std::mem::take(&mut *sessions).into_iter().collect(),
+                    }
+                };
+                let mut err = proto::FrameWriter::new(stream, proto::STDERR);
+                for (name, session) in closed {
+                    // waits for a query still running on the connection
+                    let mut session = session.lock().unwrap_or_else(PoisonError::into_inner);
+                    if session.conn.in_transaction() {
+                        match block_on(session.conn.rollback()) {
+                            Ok(()) => writeln!(out, "{name} transaction rolled back")?,
+                            Err(e) => writeln!(err, "{name} rollback failed: {e:#}")?,
+                        }
+                    }
+                    writeln!(out, "{name} connection closed")?;
+                }
+            }
+            proto::Request::Transaction {
+                name,
+                action,
+                idle_timeout,
+            } => {
+                let name = name.unwrap_or_else(|| DEFAULT_CONNECTION.to_string());
+                let session = self.session(&name)?;
+                let mut session = session.lock().unwrap_or_else(PoisonError::into_inner);
+                session.last_used = Instant::now();
+                match action {
+                    cli::TxAction::Begin => {
+                        session.ensure_not_expired(&name)?;
+                        block_on(session.conn.begin())?;
+                        session.idle_timeout = Duration::from_secs(idle_timeout);
+                        writeln!(out, "{name} transaction started")?;
+                    }
+                    cli::TxAction::Commit => {
+                        session.ensure_not_expired(&name)?;
+                        block_on(session.conn.commit())?;
+                        writeln!(out, "{name} transaction committed")?;
+                    }
+                    cli::TxAction::Rollback => {
+                        if !std::mem::take(&mut session.expired) {
+                            block_on(session.conn.rollback())?;
+                        }
+                        writeln!(out, "{name} transaction rolled back")?;
+                    }
+                }
+            }
+        }
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Rolls back transactions left open on connections idle past their timeout.
+    fn roll_back_idle(&self) {
+        let sessions = self.sessions().clone();
+        for session in sessions.values() {
+            let Ok(mut session) = session.try_lock() else {
+                continue;
+            };
+            if session.conn.in_transaction() && session.last_used.elapsed() >= session.idle_timeout
+            {
+                // left open if this fails, to be tried again on the next round
+                if block_on(session.conn.rollback()).is_ok() {
+                    session.expired = true;
+                }
+            }
+        }
+    }
+
+    fn sessions(&self) -> MutexGuard<'_, BTreeMap<String, Arc<Mutex<Session>>>> {
+        self.sessions.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn session(&self, name: &str) -> Result<Arc<Mutex<Session>>> {
+        self.sessions()
+            .get(name)
+            .cloned()
+            .ok_or_else(|| anyhow!("no open connection named `{name}`"))
+    }
+}
+
+fn write_status(
+    out: &mut impl Write,
+    sessions: Vec<(String, Arc<Mutex<Session>>)>,
+) -> io::Result<()> {
+    let mut rows = vec![["name", "server", "database", "idle", "transaction"].map(String::from)];
+    for (name, session) in sessions {
+        let mut session = match session.try_lock() {
+            Ok(session) => session,
+            Err(TryLockError::Poisoned(e)) => e.into_inner(),
+            Err(TryLockError::WouldBlock) => {
+                let busy = [
+                    name,
+                    String::new(),
+                    String::new(),
+                    "busy".into(),
+                    String::new(),
+                ];
+                rows.push(busy);
+                continue;
+            }
+        };
+        // refresh, a query may have switched databases with `USE`
+        if let Ok(database) = block_on(session.conn.database()) {
+            session.database = database;
+        }
+        let idle = fmt_duration(session.last_used.elapsed());
+        let transaction = match (session.conn.in_transaction(), session.expired) {
+            (true, _) => "open",
+            (false, true) => "rolled back (idle)",
+            (false, false) => "",
+        };
+        rows.push([
+            name,
+            session.server.clone(),
+            session.database.clone(),
+            idle,
+            transaction.to_string(),
+        ]);
+    }
+
+    let widths = (0..rows[0].len())
+        .map(|i| rows.iter().map(|r| r[i].len()).max().unwrap_or_default())
+        .collect::<Vec<_>>();
+    for row in rows {
+        let cells = row
+            .iter()
+            .zip(&widths)
+            .format(|(v, w): &(&String, &usize), f: &mut fmt::Formatter<'_>| {
+                write!(f, "{v:<w$}", w = *w)
+            })
+            .join("  ");
+        writeln!(out, "{}", cells.to_string().trim_end())?;
+    }
+    Ok(())
+}
+
+fn fmt_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    match secs {
+        0..=59 => format!("{secs}s"),
+        60..=3599 => format!("{}m{}s", secs / 60, secs % 60),
+        _ => format!("{}h{}m", secs / 3600, secs % 3600 / 60),
+    }
+}
+
+/// Framing used on the daemon socket: a tag byte, a big-endian `u32` length
+/// and the payload. Requests and replies are JSON, output is passed through raw.
+pub(crate) mod proto {
+    use std::io::{self, Read, Write};
+
+    use serde::{Deserialize, Serialize};
+
+    use crate::{cli, sqlcmd};
+
+    pub(crate) const REQUEST: u8 = b'q';
+    pub(crate) const REPLY: u8 = b'r';
+    pub(crate) const STDOUT: u8 = b'o';
+    pub(crate) const STDERR: u8 = b'e';
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub(crate) enum Request {
+        Connect {
+            name: String,
+            connection_string: String,
+        },
+        Query(cli::ArgsQuery),
+        /// Runs a script, expanded by the client since its `$(var)` may name
+        /// the client's environment variables.
+        Execute {
+            script: sqlcmd::Script,
+            args: cli::ArgsExecute,
+        },
+        List,
+        Status {
+            name: Option<String>,
+        },
+        /// Closes the named connection, or all of them if no name is given.
+        Close {
+            name: Option<String>,
+        },
+        Transaction {
+            name: Option<String>,
+            action: cli::TxAction,
+            idle_timeout: u64,
+        },
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub(crate) enum Reply {
+        Done,
+        Error(String),
+    }
+
+    pub(crate) fn write_frame(w: &mut impl Write, tag: u8, payload: &[u8]) -> io::Result<()> {
+        let len = u32::try_from(payload.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "frame too large"))?;
+        w.write_all(&[tag])?;
+        w.write_all(&len.to_be_bytes())?;
+        w.write_all(payload)?;
+        w.flush()
+    }
+
+    pub(crate) fn read_frame(r: &mut impl Read) -> io::Result<Option<(u8, Vec<u8>)>> {
+        let mut header = [0; 5];
+        match r.read_exact(&mut header) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            res => res?,
+        }
+        let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]);
+        let mut payload = vec![0; len as usize];
+        r.read_exact(&mut payload)?;
+        Ok(Some((header[0], payload)))
+    }
+
+    /// Writes everything it is given as frames with the same tag.
+    pub(crate) struct FrameWriter<W> {
+        inner: W,
+        tag: u8,
+    }
+
+    impl<W: Write> FrameWriter<W> {
+        pub(crate) fn new(inner: W, tag: u8) -> Self {
+            Self { inner, tag }
+        }
+    }
+
+    impl<W: Write> Write for FrameWriter<W> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let len = buf.len().min(u32::MAX as usize);
+            write_frame(&mut self.inner, self.tag, &buf[..len])?;
+            Ok(len)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.inner.flush()
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn frame_round_trip() -> io::Result<()> {
+            let mut buf = Vec::new();
+            write_frame(&mut buf, STDOUT, b"hello")?;
+            FrameWriter::new(&mut buf, STDERR).write_all(b"world")?;
+
+            let mut r = buf.as_slice();
+            assert_eq!(read_frame(&mut r)?, Some((STDOUT, b"hello".to_vec())));
+            assert_eq!(read_frame(&mut r)?, Some((STDERR, b"world".to_vec())));
+            assert_eq!(read_frame(&mut r)?, None);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+    use crate::sqlcmd;
+
+    /// Sends `request` to `daemon` over a socket pair, returning how
+    /// `dispatch` went and the stdout it framed for the client.
+    fn dispatch(daemon: &Daemon, request: proto::Request) -> Result<(Result<()>, String)> {
+        let (client, server) = UnixStream::pair()?;
+        proto::write_frame(&mut &client, proto::REQUEST, &serde_json::to_vec(&request)?)?;
+        let res = daemon.dispatch(&server);
+        drop(server);
+        let mut out = Vec::new();
+        let mut reader = BufReader::new(&client);
+        while let Some((tag, data)) = proto::read_frame(&mut reader)? {
+            if tag == proto::STDOUT {
+                out.extend(data);
+            }
+        }
+        Ok((res, String::from_utf8(out)?))
+    }
+
+    fn error(res: Result<()>) -> String {
+        res.err().map(|e| format!("{e:#}")).unwrap_or_default()
+    }
+
+    #[test]
+    fn dispatch_without_connections() -> Result<()> {
+        let daemon = Daemon::default();
+        let (res, out) = dispatch(&daemon, proto::Request::List)?;
+        res?;
+        assert_eq!(out, "");
+        let (res, out) = dispatch(&daemon, proto::Request::Status { name: None })?;
+        res?;
+        assert_eq!(out, "name  server  database  idle  transaction\n");
+        let (res, out) = dispatch(&daemon, proto::Request::Close { name: None })?;
+        res?;
+        assert_eq!(out, "");
+
+        let missing = "no open connection named `default`";
+        let query = cli::ArgsQuery::parse_from(["query", "-q", "SELECT 1"]);
+        let (res, _) = dispatch(&daemon, proto::Request::Query(query))?;
+        assert_eq!(error(res), missing);
+        let execute = proto::Request::Execute {
+            script: sqlcmd::Script::default(),
+            args: cli::ArgsExecute::parse_from(["execute", "-q", "SELECT 1"]),
+        };
+        let (res, _) = dispatch(&daemon, execute)?;
+        assert_eq!(error(res), missing);
+        let begin = proto::Request::Transaction {
+            name: None,
+            action: cli::TxAction::Begin,
+            idle_timeout: 300,
+        };
+        let (res, _) = dispatch(&daemon, begin)?;
+        assert_eq!(error(res), missing);
+        let close = proto::Request::Close {
+            name: Some("dev1".into()),
+        };
+        let (res, _) = dispatch(&daemon, close)?;
+        assert_eq!(error(res), "no open connection named `dev1`");
+        Ok(())
+    }
+
+    #[test]
+    fn failed_connect_opens_nothing() -> Result<()> {
+        let daemon = Daemon::default();
+        // nothing listens on port 1, so this fails without a server
+        let connect = proto::Request::Connect {
+            name: "dev1".into(),
+            connection_string: "Server=tcp:127.0.0.1,1;User=sa;Password=x".into(),
+        };
+        let (res, _) = dispatch(&daemon, connect)?;
+        assert!(res.is_err());
+        let (res, out) = dispatch(&daemon, proto::Request::List)?;
+        res?;
+        assert_eq!(out, "");
+        Ok(())
+    }
+}
@@ -0,0 +1,417 @@
+use std::{
+    io::Write,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context};
+
+use crate::{
+    cli, mssql,
+    params::{Params, Value},
+    sql, sqlcmd,
+};
+
+type Result<T> = std::result::Result<T, anyhow::Error>;
+
+/// How often progress is reported while streaming parameter rows.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Where statements are run, a server connection but for tests.
+pub(crate) trait Runner {
+    /// Runs `sql` with `params` bound, returning the rows affected by each
+    /// statement as [`mssql::QueryBuilder::execute_command`] does.
+    async fn execute_command(&mut self, sql: &str, params: Vec<Value>) -> Result<Vec<u64>>;
+}
+
+impl Runner for mssql::Connection {
+    async fn execute_command(&mut self, sql: &str, params: Vec<Value>) -> Result<Vec<u64>> {
+        let mut query = mssql::QueryBuilder::new(sql);
+        for value in params {
+            query.bind(value);
+        }
+        query.execute_command(self).await
+    }
+}
+
+/// Runs `script` on `conn` batch by batch, as split on `GO` lines. Batches
+/// without placeholders are sent as they are, not through sp_executesql, so
+/// that their session state carries over to the next batch. The rows
+/// affected by each statement go to `out`. Batches failing under
+/// `--on-error continue` or `:on error ignore` are reported on `err` as they
+/// fail and again in a summary at the end, which then fails the run. With
+/// `-s` the script runs once per parameter row instead, see [`stream`].
+pub(crate) async fn run(
+    conn: &mut impl Runner,
+    script: &sqlcmd::Script,
+    args: &cli::ArgsExecute,
+    out: &mut impl Write,
+    err: &mut impl Write,
+) -> Result<()> {
+    let (text, error_modes) = sqlcmd::ErrorModes::take(&script.text)?;
+    let batches = sql::batches(&text)?;
+    if args.stream {
+        return stream(conn, single_batch(&batches)?, args, out, err).await;
+    }
+
+    let params = Params::from_args(&args.param, args.param_format)?;
+    let mut failures = Vec::new();
+    for (idx, batch) in batches.iter().enumerate() {
+        let template = sql::Template::parse(batch.sql);
+        let sql = template.bind_sql(0);
+        let values = params.values_for(&template)?;
+        for run in 1..=batch.repeat {
+            let label = match (batches.len(), batch.repeat) {
+                (1, 1) => String::new(),
+                (1, repeat) => format!("run {run} of {repeat}: "),
+                (_, 1) => format!("batch {}: ", idx + 1),
+                (_, repeat) => format!("batch {}, run {run} of {repeat}: ", idx + 1),
+            };
+            let e = match conn.execute_command(&sql, values.clone()).await {
+                Ok(counts) => {
+                    for n in counts {
+                        writeln!(out, "{label}{}", rows_affected(n))?;
+                    }
+                    out.flush()?;
+                    continue;
+                }
+                Err(e) => e,
+            };
+            let failure = format!(
+                "batch {} at {}{}: {}",
+                idx + 1,
+                script.locate(batch.line),
+                match batch.repeat {
+                    1 => String::new(),
+                    repeat => format!(", run {run} of {repeat}"),
+                },
+                describe(&e, batch, script)
+            );
+            if error_modes.at(batch.end_line(), args.on_error) == cli::OnError::Stop {
+                bail!("{failure}");
+            }
+            writeln!(err, "{failure}")?;
+            err.flush()?;
+            failures.push(failure);
+        }
+    }
+
+    if !failures.is_empty() {
+        let runs: u32 = batches.iter().map(|batch| batch.repeat).sum();
+        writeln!(err, "{} of {runs} batches failed:", failures.len())?;
+        for failure in &failures {
+            writeln!(err, "  {failure}")?;
+        }
+        err.flush()?;
+        bail!("{} of {runs} batches failed", failures.len());
+    }
+    Ok(())
+}
+
+/// Describes why a batch failed, with the number, severity and script line
+/// of a server error.
+fn describe(e: &anyhow::Error, batch: &sql::Batch, script: &sqlcmd::Script) -> String {
+    match e.downcast_ref::<tiberius::error::Error>() {
+        Some(tiberius::error::Error::Server(token)) => format!(
+            "error {}, severity {}, {}: {}",
+            token.code(),
+            token.class(),
+            // the server counts lines from the start of the batch
+            script.locate(batch.line + (token.line() as usize).max(1) - 1),
+            token.message()
+        ),
+        _ => format!("{e:#}"),
+    }
+}
+
+/// Runs a batch once per parameter row, sending `-b` rows at a time. The
+/// total rows affected go to `out`, progress to `err`.
+async fn stream(
+    conn: &mut impl Runner,
+    batch: &str,
+    args: &cli::ArgsExecute,
+    out: &mut impl Write,
+    err: &mut impl Write,
+) -> Result<()> {
+    let rows = Params::rows_from_args(&args.param, args.param_format)?;
+    let template = sql::Template::parse(batch);
+    let chunk_size = chunk_size(args, &template);
+    let chunk_sql = template.bind_rows(chunk_size);
+
+    let mut affected = 0;
+    let mut done = 0;
+    let mut last_progress = Instant::now();
+    for chunk in rows.chunks(chunk_size) {
+        let sql = match chunk.len() {
+            n if n == chunk_size => chunk_sql.clone(),
+            n => template.bind_rows(n),
+        };
+        let mut values = Vec::new();
+        for row in chunk {
+            values.extend(row.values_for(&template)?);
+        }
+        affected += match conn.execute_command(&sql, values).await {
+            Ok(counts) => counts.iter().sum::<u64>(),
+            Err(e) => {
+                let failed = match chunk.len() {
+                    1 => format!("parameter row {}", done + 1),
+                    n => format!("parameter rows {}-{}", done + 1, done + n),
+                };
+                let run = format!("{done} of {} run", rows.len());
+                return Err(e.context(format!("{failed}, {run}")));
+            }
+        };
+        done += chunk.len();
+        if last_progress.elapsed() >= PROGRESS_INTERVAL {
+            writeln!(
+                err,
+                "{done} of {} parameter rows run, {}",
+                rows.len(),
+                rows_affected(affected)
+            )?;
+            err.flush()?;
+            last_progress = Instant::now();
+        }
+    }
+
+    let n = rows.len();
+    writeln!(out, "{n} parameter rows run, {}", rows_affected(affected))?;
+    out.flush()?;
+    Ok(())
+}
+
+/// Whether the script is to be written out as SQL rather than run, told by
+/// `-f` or the extension of the `-o` file.
+pub(crate) fn writes_sql(args: &cli::ArgsExecute) -> Result<bool> {
+    Ok(match (args.format, args.output.as_deref()) {
+        (Some(cli::ScriptFormat::Sql), _) | (None, Some("-")) => true,
+        (None, None) => false,
+        (None, Some(path)) => match Path::new(path).extension() {
+            Some(ext) if ext.eq_ignore_ascii_case("sql") => true,
+            _ => bail!("cannot tell the format of `{path}`, use -f sql"),
+        },
+    })
+}
+
+/// Writes the statements `run` would send, batched the same way, with the
+/// parameters as literals.
+pub(crate) fn write_sql(script: &str, args: &cli::ArgsExecute, out: &mut impl Write) -> Result<()> {
+    let (script, _) = sqlcmd::ErrorModes::take(script)?;
+    let batches = sql::batches(&script)?;
+    if args.stream {
+        let rows = Params::rows_from_args(&args.param, args.param_format)?;
+        let template = sql::Template::parse(single_batch(&batches)?);
+        for chunk in rows.chunks(chunk_size(args, &template)) {
+            write_filled(&template, chunk, out)?;
+        }
+    } else {
+        let params = Params::from_args(&args.param, args.param_format)?;
+        for batch in &batches {
+            let template = sql::Template::parse(batch.sql);
+            write_filled(&template, std::slice::from_ref(&params), out)?;
+            match batch.repeat {
+                1 if batches.len() == 1 => {}
+                1 => writeln!(out, "GO")?,
+                n => writeln!(out, "GO {n}")?,
+            }
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+fn write_filled(template: &sql::Template, rows: &[Params], out: &mut impl Write) -> Result<()> {
+    let mut literals = Vec::new();
+    for row in rows {
+        for value in row.values_for(template)? {
+            literals.push(value.to_literal()?);
+        }
+    }
+    let sql = template.fill_rows(rows.len(), &literals);
+    out.write_all(sql::terminated(&sql).as_bytes())?;
+    Ok(())
+}
+
+/// The one batch of a script run once per parameter row.
+fn single_batch<'a>(batches: &[sql::Batch<'a>]) -> Result<&'a str> {
+    match batches {
+        [batch] if batch.repeat == 1 => Ok(batch.sql),
+        [_] => bail!("a script run once per parameter row cannot repeat with `GO <count>`"),
+        _ => bail!(
+            "a script run once per parameter row must be a single batch, this one has {}",
+            batches.len()
+        ),
+    }
+}
+
+/// Parameter rows sent at once, cut short to stay within what the server
+/// takes in one go.
+fn chunk_size(args: &cli::ArgsExecute, template: &sql::Template) -> usize {
+    args.batch_size
+        .unwrap_or(1)
+        .clamp(1, template.max_rows(mssql::MAX_PARAMS))
+}
+
+fn rows_affected(n: u64) -> String {
+    match n {
+        1 => "1 row affected".to_string(),
+        n => format!("{n} rows affected"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use anyhow::anyhow;
+    use async_std::task::block_on;
+    use clap::Parser;
+
+    use super::*;
+
+    /// Records the statements run, answering them from `replies` in turn.
+    #[derive(Default)]
+    struct Fake {
+        sent: Vec<(String, Vec<Value>)>,
+        replies: VecDeque<Result<Vec<u64>>>,
+    }
+
+    impl Runner for Fake {
+        async fn execute_command(&mut self, sql: &str, params: Vec<Value>) -> Result<Vec<u64>> {
+            self.sent.push((sql.trim_end().to_string(), params));
+            self.replies.pop_front().unwrap_or(Ok(Vec::new()))
+        }
+    }
+
+    struct Ran {
+        sent: Vec<(String, Vec<Value>)>,
+        res: Result<()>,
+        out: String,
+        err: String,
+    }
+
+    fn run_script(
+        script: &str,
+        args: &[&str],
+        replies: impl IntoIterator<Item = Result<Vec<u64>>>,
+    ) -> Result<Ran> {
+        let args =
+            cli::ArgsExecute::parse_from(std::iter::once("execute").chain(args.iter().copied()));
+        let script = sqlcmd::expand(script, Path::new(""))?;
+        let mut fake = Fake {
+            replies: replies.into_iter().collect(),
+            ..Fake::default()
+        };
+        let (mut out, mut err) = (Vec::new(), Vec::new());
+        let res = block_on(run(&mut fake, &script, &args, &mut out, &mut err));
+        Ok(Ran {
+            sent: fake.sent,
+            res,
+            out: String::from_utf8(out)?,
+            err: String::from_utf8(err)?,
+        })
+    }
+
+    #[test]
+    fn runs_batches() -> Result<()> {
+        let script = "UPDATE t SET a = 1\nGO\nINSERT INTO t VALUES ($id)\nGO 2\n";
+        let ran = run_script(script, &["-p", "id:int=5"], [Ok(vec![3]), Ok(vec![1])])?;
+        ran.res?;
+        assert_eq!(
+            ran.out,
+            "batch 1: 3 rows affected\n\
+             batch 2, run 1 of 2: 1 row affected\n"
+        );
+        let insert = (
+            "INSERT INTO t VALUES (@P1)".to_string(),
+            vec![Value::I32(5)],
+        );
+        assert_eq!(
+            ran.sent,
+            [
+                ("UPDATE t SET a = 1".to_string(), vec![]),
+                insert.clone(),
+                insert
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn failing_batches() -> Result<()> {
+        let script = "SELECT 1\nGO\nSELECT x\nGO\nSELECT 3\n";
+        let replies = || [Ok(vec![]), Err(anyhow!("invalid column")), Ok(vec![1])];
+
+        let ran = run_script(script, &[], replies())?;
+        assert_eq!(
+            format!("{:#}", ran.res.err().unwrap_or_else(|| anyhow!("ran"))),
+            "batch 2 at line 3: invalid column"
+        );
+        assert_eq!(ran.sent.len(), 2);
+
+        let ran = run_script(script, &["--on-error", "continue"], replies())?;
+        assert_eq!(
+            format!("{:#}", ran.res.err().unwrap_or_else(|| anyhow!("ran"))),
+            "1 of 3 batches failed"
+        );
+        assert_eq!(ran.out, "batch 3: 1 row affected\n");
+        assert_eq!(
+            ran.err,
+            "batch 2 at line 3: invalid column\n\
+             1 of 3 batches failed:\n  \
+             batch 2 at line 3: invalid column\n"
+        );
+
+        // the script's own setting wins over --on-error
+        let script = format!(":on error exit\n{script}");
+        let ran = run_script(&script, &["--on-error", "continue"], replies())?;
+        assert!(ran.res.is_err());
+        assert_eq!(ran.sent.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn failing_include() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let included = dir.path().join("fix.sql");
+        std::fs::write(&included, "SELECT 1\nGO\nSELECT x\n")?;
+        let script = format!("-- fixes\n:r {}\n", included.display());
+        let ran = run_script(&script, &[], [Ok(vec![]), Err(anyhow!("invalid column"))])?;
+        let e = ran.res.err().map(|e| format!("{e:#}")).unwrap_or_default();
+        let at = format!("line 3 of `{}`", included.display());
+        assert_eq!(e, format!("batch 2 at {at}: invalid column"));
+        Ok(())
+    }
+
+    #[test]
+    fn streams_rows() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let rows = dir.path().join("rows.csv");
+        std::fs::write(&rows, "id:int\n1\n2\n3\n")?;
+        let rows = rows.to_str().context("temp path is not UTF-8")?;
+        let script = "INSERT INTO t (id) VALUES ($id)";
+        let args = ["-s", "-p", rows, "-b", "2"];
+
+        let ran = run_script(script, &args, [Ok(vec![2]), Ok(vec![1])])?;
+        ran.res?;
+        assert_eq!(ran.out, "3 parameter rows run, 3 rows affected\n");
+        assert_eq!(
+            ran.sent,
+            [
+                (
+                    "INSERT INTO t (id) VALUES (@P1),\n    (@P2)".to_string(),
+                    vec![Value::I32(1), Value::I32(2)]
+                ),
+                (
+                    "INSERT INTO t (id) VALUES (@P1)".to_string(),
+                    vec![Value::I32(3)]
+                ),
+            ]
+        );
+
+        let ran = run_script(script, &args, [Ok(vec![2]), Err(anyhow!("duplicate key"))])?;
+        let e = ran.res.err().map(|e| format!("{e:#}")).unwrap_or_default();
+        assert_eq!(e, "parameter row 3, 2 of 3 run: duplicate key");
+        Ok(())
+    }
+}
@@ -1,5 +1,7 @@
 use core::fmt;
 
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
 pub(crate) trait Join<I, S> {
     fn join(self, sep: S) -> JoinIter<I, S>;
 }
@@ -9,25 +11,25 @@ where
     S: fmt::Display,
 {
     fn join(self, sep: S) -> JoinIter<I, S> {
-        impl<I: Iterator, S: fmt::Display> fmt::Display for JoinIter<I, S>
-        where
-            I: Clone,
-            I::Item: fmt::Display,
-        {
-            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-                let mut values = self.inner.clone().peekable();
-                while let Some(v) = values.next() {
-                    write!(f, "{v}")?;
-
-                    if values.peek().is_some() {
-                        write!(f, "{}", self.sep)?;
-                    }
-                }
-                Ok(())
+        JoinIter { inner: self, sep }
+    }
+}
+
+impl<I: Iterator, S: fmt::Display> fmt::Display for JoinIter<I, S>
+where
+    I: Clone,
+    I::Item: fmt::Display,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut values = self.inner.clone().peekable();
+        while let Some(v) = values.next() {
+            write!(f, "{v}")?;
+
+            if values.peek().is_some() {
+                write!(f, "{}", self.sep)?;
             }
         }
-
-        JoinIter { inner: self, sep }
+        Ok(())
     }
 }
 
@@ -43,29 +45,6 @@ pub(crate) trait Format<I, F> {
 
 impl<I: Iterator, F> Format<I, F> for I {
     fn format(self, formatter: F) -> FormatIter<I, F> {
-        impl<T, F> fmt::Display for FormatItem<T, F>
-        where
-            F: Fn(&T, &mut fmt::Formatter<'_>) -> fmt::Result,
-        {
-            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-                (self.formatter)(&self.inner, f)
-            }
-        }
-
-        impl<I: Iterator, F> Iterator for FormatIter<I, F>
-        where
-            F: Clone,
-        {
-            type Item = FormatItem<I::Item, F>;
-
-            fn next(&mut self) -> Option<Self::Item> {
-                self.inner.next().map(|v| Self::Item {
-                    inner: v,
-                    formatter: self.formatter.clone(),
-                })
-            }
-        }
-
         FormatIter {
             inner: self,
             formatter,
@@ -73,6 +52,29 @@ impl<I: Iterator, F> Format<I, F> for I {
     }
 }
 
+impl<T, F> fmt::Display for FormatItem<T, F>
+where
+    F: Fn(&T, &mut fmt::Formatter<'_>) -> fmt::Result,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (self.formatter)(&self.inner, f)
+    }
+}
+
+impl<I: Iterator, F> Iterator for FormatIter<I, F>
+where
+    F: Clone,
+{
+    type Item = FormatItem<I::Item, F>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|v| Self::Item {
+            inner: v,
+            formatter: self.formatter.clone(),
+        })
+    }
+}
+
 #[derive(Clone)]
 pub(crate) struct FormatIter<I, F> {
     inner: I,
@@ -84,6 +86,58 @@ pub(crate) struct FormatItem<T, F> {
     formatter: F,
 }
 
+/// Columns `s` takes up on a terminal, wide characters counting twice.
+pub(crate) fn width(s: &str) -> usize {
+    s.width()
+}
+
+/// The longest start of `s` that takes up no more than `width` columns.
+pub(crate) fn truncate(s: &str, width: usize) -> &str {
+    let mut used = 0;
+    for (idx, c) in s.char_indices() {
+        used += c.width().unwrap_or(0);
+        if used > width {
+            return &s[..idx];
+        }
+    }
+    s
+}
+
+/// A decimal written out with all the digits of its scale, as in `-0.50`.
+pub(crate) fn decimal(n: tiberius::numeric::Numeric) -> String {
+    let scale = n.scale() as usize;
+    let digits = format!("{:0>width$}", n.value().unsigned_abs(), width = scale + 1);
+    let (int, frac) = digits.split_at(digits.len() - scale);
+    let sign = if n.value() < 0 { "-" } else { "" };
+    match frac {
+        "" => format!("{sign}{int}"),
+        frac => format!("{sign}{int}.{frac}"),
+    }
+}
+
+/// Two lowercase hex digits for each byte.
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Standard base64 as RFC 4648 describes, padded with `=`.
+pub(crate) fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |n, (idx, byte)| {
+            n | u32::from(*byte) << (16 - 8 * idx)
+        });
+        for idx in 0..4 {
+            match idx <= chunk.len() {
+                true => out.push(ALPHABET[(n >> (18 - 6 * idx) & 0x3f) as usize] as char),
+                false => out.push('='),
+            }
+        }
+    }
+    out
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -100,6 +154,34 @@ mod tests {
         assert_eq!(format!("{join}"), "2, 3");
     }
 
+    #[test]
+    fn display_width() {
+        assert_eq!(width("naïve"), 5);
+        assert_eq!(width("日本"), 4);
+        assert_eq!(truncate("日本語", 5), "日本");
+        assert_eq!(truncate("abc", 5), "abc");
+    }
+
+    #[test]
+    fn decimals() {
+        use tiberius::numeric::Numeric;
+        assert_eq!(decimal(Numeric::new_with_scale(110, 2)), "1.10");
+        assert_eq!(decimal(Numeric::new_with_scale(-110, 2)), "-1.10");
+        assert_eq!(decimal(Numeric::new_with_scale(-5, 1)), "-0.5");
+        assert_eq!(decimal(Numeric::new_with_scale(42, 0)), "42");
+    }
+
+    #[test]
+    fn binary() {
+        assert_eq!(hex(&[0x0a, 0xff, 0x00]), "0aff00");
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+        assert_eq!(base64(&[0xfb, 0xff]), "+/8=");
+    }
+
     #[test]
     fn format() {
         let f = |v: &_, f: &mut fmt::Formatter<'_>| write!(f, "<{v}>");
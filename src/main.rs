@@ -5,14 +5,25 @@
 #![allow(unused)]
 
 mod cli;
+mod daemon;
+mod execute;
 mod fmt_util;
+mod messages;
 mod mssql;
+mod output;
+mod param_source;
+mod params;
+mod sql;
+mod sqlcmd;
 
 use std::{
     io::{self, BufWriter, Write},
+    path::Path,
     process::exit,
 };
 
+use anyhow::Context;
+
 #[async_std::main]
 async fn main() -> anyhow::Result<()> {
     use clap::Parser;
@@ -21,57 +32,203 @@ async fn main() -> anyhow::Result<()> {
         cli::Commands::Connect(args) => connect(args).await,
         cli::Commands::Query(args) => query(args).await,
         cli::Commands::Execute(args) => execute(args).await,
+        cli::Commands::List => registry(daemon::proto::Request::List),
+        cli::Commands::Status(args) => registry(daemon::proto::Request::Status { name: args.name }),
+        cli::Commands::Close(args) => close(args),
+        cli::Commands::Tx(args) => transaction(args),
+        cli::Commands::Daemon => async_std::task::spawn_blocking(daemon::serve).await,
     }
 }
 
-async fn execute(args: cli::ArgsExecute) -> anyhow::Result<()> {
-    dbg!(args);
-    Ok(())
+async fn execute(mut args: cli::ArgsExecute) -> anyhow::Result<()> {
+    let source = cli::Source::new_any_multiline(args.script.take().unwrap_or_default());
+    // `:r` includes are relative to the script, or the working directory
+    let dir = match &source {
+        cli::Source::File(path) => Path::new(path).parent().map(Path::to_path_buf),
+        _ => None,
+    };
+    let script = sqlcmd::expand(&source.into_string()?, &dir.unwrap_or_default())?;
+
+    if execute::writes_sql(&args)? {
+        // generating the script needs no connection
+        return match args.output.as_deref() {
+            None | Some("-") => exit_on_broken_pipe(execute::write_sql(
+                &script.text,
+                &args,
+                &mut io::stdout().lock(),
+            )),
+            Some(path) => {
+                let mut file = output::AtomicFile::create(path)?;
+                execute::write_sql(&script.text, &args, &mut file)?;
+                file.commit()
+            }
+        };
+    }
+
+    let mut out = BufWriter::new(std::io::stdout());
+    let Some(conn_string) = args.connection_string.take() else {
+        let name = args.name.as_deref().unwrap_or(daemon::DEFAULT_CONNECTION);
+        let client = daemon::Client::connect()?.ok_or_else(|| {
+            anyhow::anyhow!("no connection string given and no `{name}` connection open")
+        })?;
+        // the daemon reads parameter files and writes the messages file itself,
+        // from its own working directory
+        args.param = args
+            .param
+            .into_iter()
+            .map(param_source::absolute)
+            .collect::<anyhow::Result<_>>()?;
+        args.messages = args.messages.map(absolute_path).transpose()?;
+        let res = client.request(&daemon::proto::Request::Execute { script, args }, &mut out);
+        return exit_on_broken_pipe(res);
+    };
+    let conn_string = cli::Source::new_any_line(conn_string).into_string()?;
+    let mut conn = mssql::Connection::from_string(&conn_string).await?;
+    let _messages =
+        messages::Messages::open(args.messages.as_deref(), args.messages_format, io::stderr())?
+            .capture();
+
+    let res = execute::run(&mut conn, &script, &args, &mut out, &mut io::stderr()).await;
+    exit_on_broken_pipe(res)
 }
 
 async fn query(mut args: cli::ArgsQuery) -> anyhow::Result<()> {
-    // TODO: handle background connections (-d)
-    let conn_string =
-        { cli::Source::new_any_line(args.connection_string.unwrap_or_default()).into_string()? };
-    let mut conn = mssql::Connection::from_string(&conn_string).await?;
+    let query_string =
+        cli::Source::new_any_multiline(args.query.take().unwrap_or_default()).into_string()?;
 
-    let query_string = cli::Source::new_any_multiline(args.query.unwrap_or_default()).into_string()?;
-    let query_builder = mssql::QueryBuilder::new(&query_string);
+    output::resolve_format(&mut args)?;
+    // results go to the file only once they are complete, see `finish`
+    let mut file = match args.output.take().filter(|path| path != "-") {
+        Some(path) => Some(output::AtomicFile::create(&path)?),
+        None => None,
+    };
+    let mut stdout = BufWriter::new(std::io::stdout());
+    let mut out: &mut dyn Write = match &mut file {
+        Some(file) => file,
+        None => &mut stdout,
+    };
+    let Some(conn_string) = args.connection_string.take() else {
+        // no connection string, run it on a connection held by the daemon
+        let name = args.name.as_deref().unwrap_or(daemon::DEFAULT_CONNECTION);
+        let client = daemon::Client::connect()?.ok_or_else(|| {
+            anyhow::anyhow!("no connection string given and no `{name}` connection open")
+        })?;
+        args.query = Some(query_string);
+        // the daemon reads parameter files and writes the messages file itself,
+        // from its own working directory
+        args.param = args
+            .param
+            .into_iter()
+            .map(param_source::absolute)
+            .collect::<anyhow::Result<_>>()?;
+        args.messages = args.messages.map(absolute_path).transpose()?;
+        let res = client.request(&daemon::proto::Request::Query(args), &mut out);
+        return finish(res, file);
+    };
+    let conn_string = cli::Source::new_any_line(conn_string).into_string()?;
+    let mut conn = mssql::Connection::from_string(&conn_string).await?;
+    let _messages = match output::keeps_messages(&args) {
+        true => None,
+        false => Some(
+            messages::Messages::open(args.messages.as_deref(), args.messages_format, io::stderr())?
+                .capture(),
+        ),
+    };
 
-    // TODO: bind paramters to query (-p)
-    // TODO: handle streaming parameters (-s)
+    let params = params::Params::from_args(&args.param, args.param_format)?;
+    let query_builder = mssql::QueryBuilder::with_params(&query_string, &params)?;
 
     let results = query_builder.execute(&mut conn).await?;
 
-    // TODO: implement writing to files
-    let mut out = BufWriter::new(std::io::stdout());
-    for (set_idx, result_set) in results.into_iter().enumerate() {
-        let res = match args.format.take().unwrap_or_default() {
-            cli::OutputFormat::Json => writeln!(
-                out,
-                "{}",
-                serde_json::to_string(&result_set.into_iter().collect::<Vec<_>>())?
-            ),
-            cli::OutputFormat::Text => {
-                // TODO: use markdown table format
-                writeln!(out, "result set {}:", set_idx + 1)?;
-                for row in result_set {
-                    writeln!(out, "> new row")?;
-                    for (i, (col, val)) in row.iter_columns().zip(row.iter_values()).enumerate() {
-                        writeln!(out, "{i}: {col} = {val}")?;
-                    }
-                }
-                Ok(())
-            }
-        };
-        match res {
-            Err(e) if matches!(e.kind(), io::ErrorKind::BrokenPipe) => exit(0),
-            _ => res?,
+    let format = args.format.clone().unwrap_or_default();
+    let res = output::write_results(results, &format, &args, &mut out).await;
+    finish(res, file)
+}
+
+/// Puts the `-o` file in place once the results have all been written to it.
+/// When they have not, it is left out and any earlier file kept.
+fn finish(res: anyhow::Result<()>, file: Option<output::AtomicFile>) -> anyhow::Result<()> {
+    exit_on_broken_pipe(res)?;
+    match file {
+        Some(file) => file.commit(),
+        None => Ok(()),
+    }
+}
+
+fn absolute_path(path: String) -> anyhow::Result<String> {
+    let absolute = std::path::absolute(&path).with_context(|| format!("resolving `{path}`"))?;
+    match absolute.into_os_string().into_string() {
+        Ok(absolute) => Ok(absolute),
+        Err(_) => anyhow::bail!("path of `{path}` is not valid UTF-8"),
+    }
+}
+
+/// Exits quietly when the reader of our output has gone away, e.g. `db query ... | head`.
+fn exit_on_broken_pipe(res: anyhow::Result<()>) -> anyhow::Result<()> {
+    match res {
+        Err(e)
+            if e.downcast_ref::<io::Error>()
+                .is_some_and(|e| e.kind() == io::ErrorKind::BrokenPipe) =>
+        {
+            exit(0)
         }
+        _ => res,
     }
-    Ok(())
 }
 
 async fn connect(args: cli::ArgsConnect) -> anyhow::Result<()> {
+    let conn_string =
+        cli::Source::new_any_line(args.connection_string.unwrap_or_default()).into_string()?;
+    let name = args
+        .name
+        .unwrap_or_else(|| daemon::DEFAULT_CONNECTION.to_string());
+    if args.detach {
+        let client = daemon::Client::connect_or_spawn()?;
+        let request = daemon::proto::Request::Connect {
+            name: name.clone(),
+            connection_string: conn_string,
+        };
+        client.request(&request, &mut std::io::stdout())?;
+    } else {
+        // only check that we can log in, the connection closes when dropped
+        mssql::Connection::from_string(&conn_string).await?;
+    }
+    println!("{name} connection successful");
     Ok(())
 }
+
+/// Sends a request about open connections to the daemon. Without a daemon
+/// there are no open connections and nothing to report.
+fn registry(request: daemon::proto::Request) -> anyhow::Result<()> {
+    match daemon::Client::connect()? {
+        Some(client) => client.request(&request, &mut std::io::stdout()),
+        None => Ok(()),
+    }
+}
+
+fn close(args: cli::ArgsClose) -> anyhow::Result<()> {
+    let name = match (args.all, args.name) {
+        (true, _) => None,
+        (false, name) => Some(name.unwrap_or_else(|| daemon::DEFAULT_CONNECTION.to_string())),
+    };
+    match (daemon::Client::connect()?, name) {
+        (Some(client), name) => client.request(
+            &daemon::proto::Request::Close { name },
+            &mut std::io::stdout(),
+        ),
+        (None, Some(name)) => anyhow::bail!("no open connection named `{name}`"),
+        (None, None) => Ok(()),
+    }
+}
+
+fn transaction(args: cli::ArgsTx) -> anyhow::Result<()> {
+    let name = args.name.as_deref().unwrap_or(daemon::DEFAULT_CONNECTION);
+    let client = daemon::Client::connect()?
+        .ok_or_else(|| anyhow::anyhow!("no open connection named `{name}`"))?;
+    let request = daemon::proto::Request::Transaction {
+        name: args.name,
+        action: args.action,
+        idle_timeout: args.idle_timeout,
+    };
+    client.request(&request, &mut std::io::stdout())
+}
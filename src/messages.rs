@@ -0,0 +1,199 @@
+use std::{
+    fmt,
+    io::Write,
+    sync::{Mutex, PoisonError},
+};
+
+use anyhow::Context;
+use tracing::{
+    field::{Field, Visit},
+    span, Event, Level, Metadata, Subscriber,
+};
+
+use crate::cli;
+
+type Result<T> = std::result::Result<T, anyhow::Error>;
+
+/// Target of the events tiberius logs for the tokens it reads, info messages
+/// among them.
+pub(crate) const TOKEN_TARGET: &str = "tiberius::tds::stream::token";
+
+/// How tiberius describes environment changes, which it logs with the same
+/// target and level as info messages.
+const ENV_CHANGES: &[&str] = &[
+    "Database change from ",
+    "Packet size change from ",
+    "SQL collation change",
+    "Begin transaction",
+    "Commit transaction",
+    "Rollback transaction",
+    "Defect transaction",
+    "Server requested routing to ",
+    "Fallback mirror server: ",
+    "Ignored env change: ",
+];
+
+/// Writes the informational messages of the server: `PRINT`, `RAISERROR`
+/// below severity 11, `SET STATISTICS IO` and the like.
+///
+/// tiberius drops info tokens once read, logging them as tracing events, so
+/// they are picked up as such.
+pub(crate) struct Messages {
+    sink: Mutex<Box<dyn Write + Send>>,
+    format: cli::MessageFormat,
+}
+
+impl Messages {
+    pub(crate) fn new(sink: impl Write + Send + 'static, format: cli::MessageFormat) -> Self {
+        Self {
+            sink: Mutex::new(Box::new(sink)),
+            format,
+        }
+    }
+
+    /// Messages written to the file at `path` if given, else to `stderr`.
+    pub(crate) fn open(
+        path: Option<&str>,
+        format: cli::MessageFormat,
+        stderr: impl Write + Send + 'static,
+    ) -> Result<Self> {
+        Ok(match path {
+            Some(path) => {
+                let file =
+                    std::fs::File::create(path).with_context(|| format!("creating `{path}`"))?;
+                Self::new(file, format)
+            }
+            None => Self::new(stderr, format),
+        })
+    }
+
+    /// Writes the messages received on this thread until the guard is dropped.
+    pub(crate) fn capture(self) -> tracing::subscriber::DefaultGuard {
+        tracing::subscriber::set_default(self)
+    }
+
+    fn write(&self, message: &str) -> Result<()> {
+        let mut sink = self.sink.lock().unwrap_or_else(PoisonError::into_inner);
+        match self.format {
+            cli::MessageFormat::Text => writeln!(sink, "{message}")?,
+            cli::MessageFormat::Json => {
+                serde_json::to_writer(&mut *sink, &serde_json::json!({ "message": message }))?;
+                writeln!(sink)?;
+            }
+        }
+        sink.flush()?;
+        Ok(())
+    }
+}
+
+impl Subscriber for Messages {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        is_message(metadata)
+    }
+
+    fn event(&self, event: &Event<'_>) {
+        if let Some(message) = server_message(event) {
+            // there is no one to tell if the messages cannot be written
+            let _ = self.write(&message);
+        }
+    }
+
+    fn new_span(&self, _: &span::Attributes<'_>) -> span::Id {
+        span::Id::from_u64(1)
+    }
+
+    fn record(&self, _: &span::Id, _: &span::Record<'_>) {}
+
+    fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+
+    fn enter(&self, _: &span::Id) {}
+
+    fn exit(&self, _: &span::Id) {}
+}
+
+/// Whether events of this kind may be server messages.
+pub(crate) fn is_message(metadata: &Metadata<'_>) -> bool {
+    metadata.is_event() && *metadata.level() == Level::INFO && metadata.target() == TOKEN_TARGET
+}
+
+/// The server message `event` logs, if it is one.
+pub(crate) fn server_message(event: &Event<'_>) -> Option<String> {
+    if !is_message(event.metadata()) {
+        return None;
+    }
+    let message = event_message(event);
+    let is_env_change = ENV_CHANGES.iter().any(|change| message.starts_with(change));
+    (!is_env_change).then_some(message)
+}
+
+/// The `message` field of `event`, as tiberius logs its tokens.
+pub(crate) fn event_message(event: &Event<'_>) -> String {
+    let mut visitor = MessageField::default();
+    event.record(&mut visitor);
+    visitor.0
+}
+
+#[derive(Default)]
+struct MessageField(String);
+
+impl Visit for MessageField {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.0 = value.to_string();
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            self.0 = format!("{value:?}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn info_messages() -> Result<()> {
+        let sink = Shared::default();
+        {
+            let _guard = Messages::new(sink.clone(), cli::MessageFormat::Json).capture();
+            tracing::event!(target: TOKEN_TARGET, Level::INFO, "{}", "step 1 done");
+            tracing::event!(target: TOKEN_TARGET, Level::INFO, "{}", "Begin transaction");
+            tracing::event!(target: TOKEN_TARGET, Level::TRACE, "{}", "done");
+            tracing::event!(Level::INFO, "{}", "elsewhere");
+            tracing::event!(target: TOKEN_TARGET, Level::INFO, "{}", "say \"hi\"");
+        }
+        tracing::event!(target: TOKEN_TARGET, Level::INFO, "{}", "after");
+        let written = String::from_utf8(
+            sink.0
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .clone(),
+        )?;
+        assert_eq!(
+            written,
+            "{\"message\":\"step 1 done\"}\n{\"message\":\"say \\\"hi\\\"\"}\n"
+        );
+        Ok(())
+    }
+}
@@ -1,14 +1,29 @@
-use anyhow::Context;
-use async_std::net::TcpStream;
+use std::{
+    borrow::Cow,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context};
+use async_std::{net::TcpStream, stream::StreamExt};
+
+use crate::{params, sql};
 
 type Result<T> = std::result::Result<T, anyhow::Error>;
 
+/// Most bind parameters one statement can take. sp_executesql allows 2100
+/// arguments, two of which are the statement and its parameter declarations.
+pub(crate) const MAX_PARAMS: usize = 2098;
+
 pub(crate) struct Connection {
-    _config: tiberius::Config,
+    config: tiberius::Config,
     client: tiberius::Client<TcpStream>,
+    transaction: Transaction,
 }
 
-pub(crate) struct QueryBuilder<'a>(tiberius::Query<'a>);
+pub(crate) struct QueryBuilder<'a> {
+    sql: Cow<'a, str>,
+    params: Vec<params::Value>,
+}
 
 impl Connection {
     pub(crate) async fn from_string(conn_str: &str) -> Result<Self> {
@@ -20,72 +35,260 @@ impl Connection {
         let client = tiberius::Client::connect(config.clone(), tcp).await?;
 
         Ok(Self {
-            _config: config,
+            config,
             client,
+            transaction: Transaction::default(),
         })
     }
-}
 
-impl<'a> QueryBuilder<'a> {
-    pub(crate) fn new(query_string: &'a str) -> Self {
-        Self(tiberius::Query::new(query_string))
+    /// Address of the server this connection was opened to.
+    pub(crate) fn server(&self) -> String {
+        self.config.get_addr()
     }
-    pub(crate) async fn execute(self, connection: &mut Connection) -> Result<QueryResults> {
-        let stream = self.0.query(&mut connection.client).await?;
-        let results = stream.into_results().await?;
-        Ok(QueryResults::new(results))
+
+    /// Name of the database currently in use on this connection.
+    pub(crate) async fn database(&mut self) -> Result<String> {
+        let row = self
+            .client
+            .simple_query("SELECT DB_NAME()")
+            .await?
+            .into_row()
+            .await?;
+        Ok(row
+            .and_then(|row| row.get::<&str, _>(0).map(str::to_string))
+            .unwrap_or_default())
+    }
+
+    /// Whether a transaction started with [`Connection::begin`] is still open.
+    pub(crate) fn in_transaction(&self) -> bool {
+        self.transaction.0
+    }
+
+    pub(crate) async fn begin(&mut self) -> Result<()> {
+        self.transaction.begin(&mut self.client).await
+    }
+
+    pub(crate) async fn commit(&mut self) -> Result<()> {
+        self.transaction.commit(&mut self.client).await
+    }
+
+    pub(crate) async fn rollback(&mut self) -> Result<()> {
+        self.transaction.rollback(&mut self.client).await
     }
 }
 
-pub(crate) struct QueryResults(Vec<Vec<tiberius::Row>>);
+/// Where the statements beginning and ending transactions are run, a
+/// server connection but for tests.
+trait Batch {
+    async fn batch(&mut self, sql: &str) -> Result<()>;
+}
 
-impl QueryResults {
-    fn new(results: Vec<Vec<tiberius::Row>>) -> Self {
-        Self(results)
+impl Batch for tiberius::Client<TcpStream> {
+    async fn batch(&mut self, sql: &str) -> Result<()> {
+        self.simple_query(sql).await?.into_results().await?;
+        Ok(())
     }
 }
 
-pub(crate) struct ResultSetIter(Vec<Vec<tiberius::Row>>);
+/// Whether a transaction begun on the connection is open, only changed once
+/// the server has done so.
+#[derive(Default)]
+struct Transaction(bool);
 
-impl IntoIterator for QueryResults {
-    type Item = ResultSet;
-    type IntoIter = ResultSetIter;
+impl Transaction {
+    async fn begin(&mut self, conn: &mut impl Batch) -> Result<()> {
+        if self.0 {
+            bail!("a transaction is already open");
+        }
+        conn.batch("BEGIN TRANSACTION").await?;
+        self.0 = true;
+        Ok(())
+    }
 
-    fn into_iter(mut self) -> Self::IntoIter {
-        self.0.reverse();
-        ResultSetIter(self.0)
+    async fn commit(&mut self, conn: &mut impl Batch) -> Result<()> {
+        if !self.0 {
+            bail!("no open transaction");
+        }
+        if let Err(e) = conn.batch("COMMIT TRANSACTION").await {
+            // a doomed transaction cannot be committed, but is open until rolled back
+            return match self.rollback(conn).await {
+                Ok(()) => Err(e.context("commit failed, transaction rolled back")),
+                Err(_) => Err(e.context("commit failed, transaction still open")),
+            };
+        }
+        self.0 = false;
+        Ok(())
+    }
+
+    async fn rollback(&mut self, conn: &mut impl Batch) -> Result<()> {
+        if !self.0 {
+            bail!("no open transaction");
+        }
+        // the server may have rolled back already, e.g. after an error with XACT_ABORT
+        conn.batch("IF @@TRANCOUNT > 0 ROLLBACK TRANSACTION")
+            .await?;
+        self.0 = false;
+        Ok(())
     }
 }
 
-impl Iterator for ResultSetIter {
-    type Item = ResultSet;
+impl<'a> QueryBuilder<'a> {
+    pub(crate) fn new(query_string: &'a str) -> Self {
+        Self {
+            sql: query_string.into(),
+            params: Vec::new(),
+        }
+    }
+    /// Rewrites `$name` placeholders into bind parameters, binding their values from `params`.
+    pub(crate) fn with_params(query_string: &'a str, params: &params::Params) -> Result<Self> {
+        let template = sql::Template::parse(query_string);
+        if template.names().is_empty() {
+            return Ok(Self::new(query_string));
+        }
+        Ok(Self {
+            sql: template.bind_sql(0).into(),
+            params: params.values_for(&template)?,
+        })
+    }
+    pub(crate) fn bind(&mut self, param: params::Value) {
+        self.params.push(param);
+    }
+    pub(crate) async fn execute<'c>(
+        self,
+        connection: &'c mut Connection,
+    ) -> Result<QueryResults<'c>> {
+        // column details beyond name and type only show in tiberius' log of
+        // the tokens it reads, so that is listened to while the query runs
+        let started = Instant::now();
+        let recorder = metadata::Recorder::install();
+        // Bound parameters need sp_executesql, which scopes temp tables, SET
+        // options and USE to the call. Plain batches leave them on the session
+        // so they are still there for the next query on the same connection.
+        let stream = if self.params.is_empty() {
+            connection.client.simple_query(self.sql).await?
+        } else {
+            let mut query = tiberius::Query::new(self.sql);
+            for param in self.params {
+                query.bind(param);
+            }
+            query.query(&mut connection.client).await?
+        };
+        Ok(QueryResults::new(stream, recorder, started))
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        self.0.pop().map(ResultSet)
+    /// Runs statements for their effect, returning the number of rows
+    /// affected by each of them that the server gave a count for, which
+    /// leaves out `SET`, `DECLARE` and DDL statements.
+    pub(crate) async fn execute_command(self, connection: &mut Connection) -> Result<Vec<u64>> {
+        // tiberius counts every done token, counted or not, so the counts
+        // are taken from its log of them instead
+        let recorder = metadata::Recorder::install();
+        // as for `execute`, only bound parameters go through sp_executesql so
+        // that a plain batch leaves its session state behind
+        if self.params.is_empty() {
+            let mut stream = connection.client.simple_query(self.sql).await?;
+            // the rows of any result sets are not wanted, only the done tokens
+            while stream.next().await.transpose()?.is_some() {}
+        } else {
+            let mut query = tiberius::Query::new(self.sql);
+            for param in self.params {
+                query.bind(param);
+            }
+            query.execute(&mut connection.client).await?;
+        }
+        recorder.rows_affected()
     }
 }
 
-#[derive(Debug)]
-pub(crate) struct ResultSet(Vec<tiberius::Row>);
-pub(crate) struct ResultRowIter(Vec<tiberius::Row>);
+/// Results of a query, read from the server as they are asked for so that
+/// only the row at hand is held in memory.
+pub(crate) struct QueryResults<'a> {
+    stream: tiberius::QueryStream<'a>,
+    /// Columns of a result set that has been reached but not yet handed out.
+    next_columns: Option<Vec<metadata::Column>>,
+    recorder: metadata::Recorder,
+    started: Instant,
+}
 
-impl IntoIterator for ResultSet {
-    type Item = ResultRow;
-    type IntoIter = ResultRowIter;
+impl<'a> QueryResults<'a> {
+    fn new(
+        stream: tiberius::QueryStream<'a>,
+        recorder: metadata::Recorder,
+        started: Instant,
+    ) -> Self {
+        Self {
+            stream,
+            next_columns: None,
+            recorder,
+            started,
+        }
+    }
+
+    /// Rows affected by each statement that returned no rows, complete once
+    /// the last result set has been read.
+    pub(crate) fn rows_affected(&self) -> Result<Vec<u64>> {
+        self.recorder.rows_affected()
+    }
 
-    fn into_iter(mut self) -> Self::IntoIter {
-        self.0.reverse();
-        ResultRowIter(self.0)
+    /// Info messages of the server, complete once the last result set has
+    /// been read.
+    pub(crate) fn messages(&self) -> Vec<String> {
+        self.recorder.messages()
+    }
+
+    /// Time since the query was sent.
+    pub(crate) fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Moves on to the next result set, skipping what is left of the current one.
+    pub(crate) async fn next_set(&mut self) -> Result<Option<ResultSet<'_, 'a>>> {
+        loop {
+            if let Some(columns) = self.next_columns.take() {
+                return Ok(Some(ResultSet {
+                    results: self,
+                    columns,
+                }));
+            }
+            match self.stream.next().await.transpose()? {
+                Some(tiberius::QueryItem::Metadata(meta)) => {
+                    self.next_columns = Some(self.recorder.columns(meta.columns()))
+                }
+                Some(tiberius::QueryItem::Row(_)) => {}
+                None => return Ok(None),
+            }
+        }
     }
 }
 
-impl Iterator for ResultRowIter {
-    type Item = ResultRow;
+/// One result set of [`QueryResults`], its rows read one at a time.
+pub(crate) struct ResultSet<'r, 'a> {
+    results: &'r mut QueryResults<'a>,
+    columns: Vec<metadata::Column>,
+}
 
-    fn next(&mut self) -> Option<Self::Item> {
-        self.0.pop().map(ResultRow)
+impl ResultSet<'_, '_> {
+    /// Columns of the set, known even when it has no rows.
+    pub(crate) fn columns(&self) -> &[metadata::Column] {
+        &self.columns
+    }
+
+    /// The next row of the set, `None` once the set has ended.
+    pub(crate) async fn next_row(&mut self) -> Result<Option<ResultRow>> {
+        if self.results.next_columns.is_some() {
+            return Ok(None);
+        }
+        match self.results.stream.next().await.transpose()? {
+            Some(tiberius::QueryItem::Row(row)) => Ok(Some(ResultRow(row))),
+            Some(tiberius::QueryItem::Metadata(meta)) => {
+                self.results.next_columns = Some(self.results.recorder.columns(meta.columns()));
+                Ok(None)
+            }
+            None => Ok(None),
+        }
     }
 }
+
 pub(crate) struct ResultRow(tiberius::Row);
 
 struct ResultValueIter<'a> {
@@ -145,6 +348,77 @@ impl<'a> ResultRow {
 
 #[derive(Debug, Clone)]
 pub(crate) struct ResultValueRef<'a, 'b>(&'a tiberius::ColumnData<'b>);
+
+impl ResultValueRef<'_, '_> {
+    /// The value as a parameter, to be written back as a literal.
+    pub(crate) fn to_param(&self) -> params::Value {
+        use chrono::{DateTime, FixedOffset, NaiveDate, NaiveDateTime, NaiveTime};
+        use params::Value as V;
+        use tiberius::FromSql;
+
+        type C<'a> = tiberius::ColumnData<'a>;
+        let date_time =
+            |d: &C<'static>| NaiveDateTime::from_sql(d).ok().flatten().map(V::DateTime2);
+        let value = match self.0 {
+            C::U8(v) => v.map(V::U8),
+            C::I16(v) => v.map(V::I16),
+            C::I32(v) => v.map(V::I32),
+            C::I64(v) => v.map(V::I64),
+            C::F32(v) => v.map(V::F32),
+            C::F64(v) => v.map(V::F64),
+            C::Bit(v) => v.map(V::Bit),
+            C::String(v) => v.as_ref().map(|s| V::String(s.to_string())),
+            C::Guid(v) => v.map(V::Guid),
+            C::Binary(v) => v.as_ref().map(|b| V::Binary(b.to_vec())),
+            C::Numeric(v) => v.map(V::Numeric),
+            C::Xml(v) => v
+                .as_ref()
+                .map(|x| V::String(x.as_ref().as_ref().to_string())),
+            // the date and time types are read from copies, as `FromSql`
+            // only takes `ColumnData<'static>`
+            C::Date(d) => NaiveDate::from_sql(&C::Date(*d))
+                .ok()
+                .flatten()
+                .map(V::Date),
+            C::Time(t) => NaiveTime::from_sql(&C::Time(*t))
+                .ok()
+                .flatten()
+                .map(V::Time),
+            C::DateTime(d) => date_time(&C::DateTime(*d)),
+            C::SmallDateTime(d) => date_time(&C::SmallDateTime(*d)),
+            C::DateTime2(d) => date_time(&C::DateTime2(*d)),
+            C::DateTimeOffset(d) => DateTime::<FixedOffset>::from_sql(&C::DateTimeOffset(*d))
+                .ok()
+                .flatten()
+                .map(V::DateTimeOffset),
+        };
+        value.unwrap_or(V::Null(params::Type::default()))
+    }
+
+    pub(crate) fn is_null(&self) -> bool {
+        type C<'a> = tiberius::ColumnData<'a>;
+        match self.0 {
+            C::U8(v) => v.is_none(),
+            C::I16(v) => v.is_none(),
+            C::I32(v) => v.is_none(),
+            C::I64(v) => v.is_none(),
+            C::F32(v) => v.is_none(),
+            C::F64(v) => v.is_none(),
+            C::Bit(v) => v.is_none(),
+            C::String(v) => v.is_none(),
+            C::Guid(v) => v.is_none(),
+            C::Binary(v) => v.is_none(),
+            C::Numeric(v) => v.is_none(),
+            C::Xml(v) => v.is_none(),
+            C::DateTime(v) => v.is_none(),
+            C::SmallDateTime(v) => v.is_none(),
+            C::Time(v) => v.is_none(),
+            C::Date(v) => v.is_none(),
+            C::DateTime2(v) => v.is_none(),
+            C::DateTimeOffset(v) => v.is_none(),
+        }
+    }
+}
 impl<'a, 'b> tiberius::FromSql<'a> for ResultValueRef<'a, 'b> {
     fn from_sql(value: &'a tiberius::ColumnData<'static>) -> tiberius::Result<Option<Self>> {
         Ok(Some(ResultValueRef(value)))
@@ -154,14 +428,40 @@ impl<'a, 'b> tiberius::FromSql<'a> for ResultValueRef<'a, 'b> {
 pub(crate) mod fmt {
     use std::fmt::Display;
 
-    use chrono::{DateTime, FixedOffset, NaiveDate, NaiveDateTime, NaiveTime};
+    use chrono::{DateTime, FixedOffset, NaiveDate, NaiveDateTime, NaiveTime, Timelike};
 
     use super::{ResultValueOwned, ResultValueRef};
+    use crate::{cli, fmt_util};
 
-    impl<'a, 'b> Display for ResultValueRef<'a, 'b> {
+    /// A value written out with binary encoded as `--binary` tells.
+    pub(crate) struct Text<'a, 'b> {
+        value: ResultValueRef<'a, 'b>,
+        binary: cli::Binary,
+    }
+
+    impl<'a, 'b> ResultValueRef<'a, 'b> {
+        pub(crate) fn text(&self, binary: cli::Binary) -> Text<'a, 'b> {
+            Text {
+                value: self.clone(),
+                binary,
+            }
+        }
+    }
+
+    /// Binary is written as hex, see [`Text`] for the other encodings.
+    impl Display for ResultValueRef<'_, '_> {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            self.text(cli::Binary::default()).fmt(f)
+        }
+    }
+
+    /// A width, as in `{:20}`, is the most columns text and binary may take
+    /// up, longer values being cut short with `...`. Other values are never
+    /// cut or padded.
+    impl Display for Text<'_, '_> {
         fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
             type C<'a> = tiberius::ColumnData<'a>;
-            match &self.0 {
+            match &self.value.0 {
                 C::U8(u) => fmt_std(f, u),
                 C::I16(i) => fmt_std(f, i),
                 C::I32(i) => fmt_std(f, i),
@@ -171,8 +471,8 @@ pub(crate) mod fmt {
                 C::Bit(b) => fmt_std(f, b),
                 C::String(s) => fmt_str(f, s),
                 C::Guid(g) => fmt_std(f, g),
-                C::Binary(b) => fmt_hex(f, b),
-                C::Numeric(n) => fmt_std(f, n),
+                C::Binary(b) => fmt_binary(f, b, self.binary),
+                C::Numeric(n) => fmt_std(f, &n.map(fmt_util::decimal)),
                 C::Xml(x) => fmt_xml(f, x),
                 C::DateTime(d) => fmt_date(f, DateFormat::DateTime(d)),
                 C::SmallDateTime(d) => fmt_date(f, DateFormat::SmallDateTime(d)),
@@ -205,19 +505,28 @@ pub(crate) mod fmt {
     ) -> std::result::Result<(), std::fmt::Error> {
         match d {
             DateFormat::DateTimeOffset(d) => {
+                let scale = d.map(|d| d.datetime2().time().scale());
                 let d = tiberius::ColumnData::DateTimeOffset(*d);
                 let dt = <DateTime<FixedOffset> as tiberius::FromSql>::from_sql(&d);
-                match dt {
-                    Ok(Some(dt)) => write!(f, "{}", dt.format("%+")),
+                match (dt, scale) {
+                    (Ok(Some(dt)), Some(scale)) => write!(
+                        f,
+                        "{}T{}{}",
+                        dt.format("%Y-%m-%d"),
+                        time(dt.time(), scale),
+                        dt.format("%:z")
+                    ),
                     _ => fmt_null(f),
                 }
             }
             DateFormat::DateTime2(d) => {
+                let scale = d.map(|d| d.time().scale());
                 let d = tiberius::ColumnData::DateTime2(*d);
                 let dt = <NaiveDateTime as tiberius::FromSql>::from_sql(&d);
-                match dt {
-                    // FIXME: should be able to handle 7 digits of sub-second precision
-                    Ok(Some(dt)) => write!(f, "{}", dt.format("%Y-%m-%dT%H:%M:%S%.6f")),
+                match (dt, scale) {
+                    (Ok(Some(dt)), Some(scale)) => {
+                        write!(f, "{}T{}", dt.format("%Y-%m-%d"), time(dt.time(), scale))
+                    }
                     _ => fmt_null(f),
                 }
             }
@@ -238,10 +547,11 @@ pub(crate) mod fmt {
                 }
             }
             DateFormat::Time(t) => {
+                let scale = t.map(|t| t.scale());
                 let d = tiberius::ColumnData::Time(*t);
                 let dt = <NaiveTime as tiberius::FromSql>::from_sql(&d);
-                match dt {
-                    Ok(Some(dt)) => write!(f, "{}", dt.format("%H:%M:%S%.3f")),
+                match (dt, scale) {
+                    (Ok(Some(dt)), Some(scale)) => write!(f, "{}", time(dt, scale)),
                     _ => fmt_null(f),
                 }
             }
@@ -256,6 +566,21 @@ pub(crate) mod fmt {
         }
     }
 
+    /// `hh:mm:ss` with as many fractional digits as the column's scale, the
+    /// 100ns of a `time(7)` included.
+    fn time(t: NaiveTime, scale: u8) -> String {
+        let digits = u32::from(scale.min(9));
+        match digits {
+            0 => t.format("%H:%M:%S").to_string(),
+            digits => format!(
+                "{}.{:0width$}",
+                t.format("%H:%M:%S"),
+                t.nanosecond() / 10u32.pow(9 - digits),
+                width = digits as usize
+            ),
+        }
+    }
+
     fn fmt_xml(
         f: &mut std::fmt::Formatter,
         x: &Option<impl AsRef<tiberius::xml::XmlData>>,
@@ -271,7 +596,7 @@ pub(crate) mod fmt {
         val: &Option<impl Display>,
     ) -> std::result::Result<(), std::fmt::Error> {
         match val {
-            Some(i) => i.fmt(f),
+            Some(i) => write!(f, "{i}"),
             None => fmt_null(f),
         }
     }
@@ -284,38 +609,746 @@ pub(crate) mod fmt {
         f: &mut std::fmt::Formatter,
         s: &Option<impl AsRef<str>>,
     ) -> std::result::Result<(), std::fmt::Error> {
-        let width = f.width().unwrap_or(usize::MAX);
         match s {
-            Some(s) => {
-                let (s, postfix) = if s.as_ref().len() > width - 2 {
-                    (&s.as_ref()[..width - 3], "...")
-                } else {
-                    (s.as_ref(), "")
-                };
-                write!(f, "{s}{postfix}")
-            }
+            Some(s) => match f.width() {
+                Some(width) if fmt_util::width(s.as_ref()) > width => {
+                    let s = fmt_util::truncate(s.as_ref(), width.saturating_sub(3));
+                    write!(f, "{s}...")
+                }
+                _ => write!(f, "{}", s.as_ref()),
+            },
             None => fmt_null(f),
         }
     }
 
-    fn fmt_hex(
+    fn fmt_binary(
         f: &mut std::fmt::Formatter,
         b: &Option<impl AsRef<[u8]>>,
+        encoding: cli::Binary,
     ) -> std::result::Result<(), std::fmt::Error> {
-        let width = f.width().unwrap_or(usize::MAX);
-        match b {
-            Some(b) => {
-                let (b, postfix) = if b.as_ref().len() > width {
-                    (&b.as_ref()[..width / 2 - 2], "...")
+        let Some(b) = b else {
+            return fmt_null(f);
+        };
+        let b = b.as_ref();
+        let text = binary(b, encoding);
+        match f.width() {
+            Some(width) if text.len() > width => {
+                // whole bytes are left, in base64 whole groups of three
+                let room = width.saturating_sub(3);
+                let len = match encoding {
+                    cli::Binary::Hex => room / 2,
+                    cli::Binary::Prefixed => room.saturating_sub(2) / 2,
+                    cli::Binary::Base64 => room / 4 * 3,
+                    cli::Binary::Omit => 0,
+                };
+                match len.min(b.len()) {
+                    // too narrow for a byte, and a bare `0x` would not fit either
+                    0 => write!(f, "..."),
+                    len => write!(f, "{}...", binary(&b[..len], encoding)),
+                }
+            }
+            _ => write!(f, "{text}"),
+        }
+    }
+
+    /// `bytes` encoded as `--binary` tells.
+    pub(crate) fn binary(bytes: &[u8], encoding: cli::Binary) -> String {
+        match encoding {
+            cli::Binary::Hex => fmt_util::hex(bytes),
+            cli::Binary::Base64 => fmt_util::base64(bytes),
+            cli::Binary::Prefixed => format!("0x{}", fmt_util::hex(bytes).to_uppercase()),
+            cli::Binary::Omit => String::new(),
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn binary_text() {
+            let data = tiberius::ColumnData::Binary(Some(vec![0x0a, 0xff, 0x00, 0x01].into()));
+            let text = |binary| ResultValueRef(&data).text(binary);
+            assert_eq!(ResultValueRef(&data).to_string(), "0aff0001");
+            assert_eq!(text(cli::Binary::Prefixed).to_string(), "0x0AFF0001");
+            assert_eq!(text(cli::Binary::Base64).to_string(), "Cv8AAQ==");
+            assert_eq!(text(cli::Binary::Omit).to_string(), "");
+            // cut between whole bytes, within the width
+            assert_eq!(format!("{:8}", text(cli::Binary::Hex)), "0aff0001");
+            assert_eq!(format!("{:7}", text(cli::Binary::Hex)), "0aff...");
+            assert_eq!(format!("{:8}", text(cli::Binary::Prefixed)), "0x0A...");
+            assert_eq!(format!("{:7}", text(cli::Binary::Base64)), "Cv8A...");
+            assert_eq!(format!("{:7}", text(cli::Binary::Prefixed)), "0x0A...");
+            assert_eq!(format!("{:6}", text(cli::Binary::Prefixed)), "...");
+            assert_eq!(format!("{:4}", text(cli::Binary::Prefixed)), "...");
+            assert_eq!(format!("{:3}", text(cli::Binary::Prefixed)), "...");
+            assert_eq!(format!("{:4}", text(cli::Binary::Hex)), "...");
+            assert_eq!(format!("{:3}", text(cli::Binary::Hex)), "...");
+        }
+
+        #[test]
+        fn date_scales() {
+            use tiberius::{
+                time::{Date, DateTime2, DateTimeOffset, Time},
+                ColumnData as C,
+            };
+            let text = |data: C<'_>| ResultValueRef(&data).to_string();
+            // 12:34:56.1234567, 2024-02-29
+            let time =
+                |scale: u8| Time::new(452_961_234_567 / 10u64.pow(7 - u32::from(scale)), scale);
+            let date = Date::new(738_944);
+            assert_eq!(text(C::Time(Some(time(7)))), "12:34:56.1234567");
+            assert_eq!(text(C::Time(Some(time(3)))), "12:34:56.123");
+            assert_eq!(text(C::Time(Some(time(0)))), "12:34:56");
+            assert_eq!(
+                text(C::DateTime2(Some(DateTime2::new(date, time(7))))),
+                "2024-02-29T12:34:56.1234567"
+            );
+            assert_eq!(
+                text(C::DateTimeOffset(Some(DateTimeOffset::new(
+                    DateTime2::new(date, time(1)),
+                    90
+                )))),
+                "2024-02-29T14:04:56.1+01:30"
+            );
+            assert_eq!(text(C::Time(None)), "null");
+        }
+    }
+}
+
+pub(crate) mod metadata {
+    use std::{
+        collections::VecDeque,
+        fmt,
+        sync::{Arc, Mutex, PoisonError},
+    };
+
+    use anyhow::anyhow;
+    use serde::Serialize;
+    use tracing::{
+        dispatcher::{self, Dispatch},
+        field::{Field, Visit},
+        span,
+        subscriber::{DefaultGuard, Interest},
+        Event, Level, Metadata, Subscriber,
+    };
+
+    use super::Result;
+    use crate::messages::{self, TOKEN_TARGET};
+
+    /// A column of a result set, as described by the server before any rows.
+    #[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+    pub(crate) struct Column {
+        pub name: String,
+        /// The type as SQL Server spells it, e.g. `decimal(10,2)` or `nvarchar(max)`.
+        pub sql_type: String,
+        /// `None` where tiberius did not let on, see [`Recorder`].
+        pub nullable: Option<bool>,
+        pub precision: Option<u8>,
+        pub scale: Option<u8>,
+    }
+
+    impl Column {
+        /// Whether the column holds numbers, which read better right-aligned.
+        pub(crate) fn is_numeric(&self) -> bool {
+            let base = self.sql_type.split('(').next().unwrap_or_default();
+            matches!(
+                base,
+                "tinyint"
+                    | "smallint"
+                    | "int"
+                    | "bigint"
+                    | "real"
+                    | "float"
+                    | "decimal"
+                    | "numeric"
+                    | "money"
+                    | "smallmoney"
+            )
+        }
+    }
+
+    /// The column tokens tiberius logs, in the order it reads them, along
+    /// with the row counts of statements and the server's info messages.
+    ///
+    /// tiberius only hands out the name and a coarse type of each column, the
+    /// rest of the column token is in the trace event it logs on reading it.
+    /// Row counts and messages of a query it drops altogether, but for their
+    /// events. Other events are passed on to the subscriber that was in
+    /// effect before.
+    pub(crate) struct Recorder {
+        log: Arc<Mutex<Log>>,
+        _guard: DefaultGuard,
+    }
+
+    #[derive(Default)]
+    struct Log {
+        tokens: VecDeque<String>,
+        /// Whether a result set has started since the last done token, which
+        /// then counts its rows rather than rows affected.
+        in_set: bool,
+        rows_affected: Vec<u64>,
+        /// Why a done token could not be read, the first time one could not,
+        /// which leaves the row counts incomplete.
+        unreadable: Option<String>,
+        messages: Vec<String>,
+    }
+
+    impl Recorder {
+        /// Starts recording the tokens read on this thread.
+        pub(crate) fn install() -> Self {
+            let log = Arc::new(Mutex::new(Log::default()));
+            let subscriber = Tokens {
+                next: dispatcher::get_default(Dispatch::clone),
+                log: log.clone(),
+            };
+            Self {
+                log,
+                _guard: tracing::subscriber::set_default(subscriber),
+            }
+        }
+
+        fn log(&self) -> std::sync::MutexGuard<'_, Log> {
+            self.log.lock().unwrap_or_else(PoisonError::into_inner)
+        }
+
+        /// Rows affected by each statement not returning rows, so far.
+        pub(crate) fn rows_affected(&self) -> Result<Vec<u64>> {
+            let log = self.log();
+            match &log.unreadable {
+                Some(e) => Err(anyhow!("cannot count rows affected: {e}")),
+                None => Ok(log.rows_affected.clone()),
+            }
+        }
+
+        /// Info messages of the server, so far.
+        pub(crate) fn messages(&self) -> Vec<String> {
+            self.log().messages.clone()
+        }
+
+        /// Describes the columns of the result set tiberius just started,
+        /// from the oldest column token not yet used.
+        pub(crate) fn columns(&self, columns: &[tiberius::Column]) -> Vec<Column> {
+            let token = self.log().tokens.pop_front();
+            let mut details = token.map(|token| parse(&token)).unwrap_or_default();
+            // the token lists exactly the columns tiberius hands out
+            if details.len() != columns.len() {
+                details.clear();
+            }
+            let unknown = Details::default();
+            columns
+                .iter()
+                .enumerate()
+                .map(|(idx, column)| details.get(idx).unwrap_or(&unknown).column(column))
+                .collect()
+        }
+    }
+
+    struct Tokens {
+        next: Dispatch,
+        log: Arc<Mutex<Log>>,
+    }
+
+    fn is_token(metadata: &Metadata<'_>) -> bool {
+        metadata.is_event()
+            && metadata.target() == TOKEN_TARGET
+            && matches!(*metadata.level(), Level::TRACE | Level::INFO)
+    }
+
+    impl Subscriber for Tokens {
+        fn register_callsite(&self, metadata: &'static Metadata<'static>) -> Interest {
+            match is_token(metadata) {
+                true => Interest::always(),
+                false => self.next.register_callsite(metadata),
+            }
+        }
+
+        fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+            is_token(metadata) || self.next.enabled(metadata)
+        }
+
+        fn event(&self, event: &Event<'_>) {
+            let metadata = event.metadata();
+            if is_token(metadata) {
+                let mut log = self.log.lock().unwrap_or_else(PoisonError::into_inner);
+                if metadata.fields().field("meta").is_some() {
+                    let mut visitor = MetaField::default();
+                    event.record(&mut visitor);
+                    log.tokens.push_back(visitor.0);
+                    log.in_set = true;
+                } else if let Some(message) = messages::server_message(event) {
+                    log.messages.push(message);
+                } else {
+                    match done_token(&DoneField::read(event)) {
+                        Some(Ok(done)) => {
+                            if let (Some(rows), false) = (done, log.in_set) {
+                                log.rows_affected.push(rows);
+                            }
+                            log.in_set = false;
+                        }
+                        Some(Err(e)) => {
+                            log.unreadable.get_or_insert(e.to_string());
+                        }
+                        None => {}
+                    }
+                }
+            }
+            if self.next.enabled(event.metadata()) {
+                self.next.event(event);
+            }
+        }
+
+        fn new_span(&self, span: &span::Attributes<'_>) -> span::Id {
+            self.next.new_span(span)
+        }
+
+        fn record(&self, span: &span::Id, values: &span::Record<'_>) {
+            self.next.record(span, values)
+        }
+
+        fn record_follows_from(&self, span: &span::Id, follows: &span::Id) {
+            self.next.record_follows_from(span, follows)
+        }
+
+        fn enter(&self, span: &span::Id) {
+            self.next.enter(span)
+        }
+
+        fn exit(&self, span: &span::Id) {
+            self.next.exit(span)
+        }
+
+        fn clone_span(&self, id: &span::Id) -> span::Id {
+            self.next.clone_span(id)
+        }
+
+        fn try_close(&self, id: span::Id) -> bool {
+            self.next.try_close(id)
+        }
+    }
+
+    const DONE: &str = "Done with status ";
+
+    /// `DoneStatus::Count` of tiberius, set on done tokens with a row count.
+    const COUNT: u16 = 1 << 4;
+
+    /// Reads the done token tiberius logs at the end of each statement, as in
+    /// `Done with status BitFlags<DoneStatus>(0b10001, More | Count) (3 rows
+    /// left)`, into its row count if it has one. `None` for other messages,
+    /// and an error for one that starts like a done token but cannot be
+    /// read, as would happen if tiberius or enumflags2 wrote them otherwise.
+    fn done_token(message: &str) -> Option<Result<Option<u64>>> {
+        let status = message.strip_prefix(DONE)?;
+        let read = || {
+            let (flags, rows) = match status.rsplit_once(" (") {
+                Some((flags, rows)) => (flags, Some(rows)),
+                None => (status, None),
+            };
+            let bits = flags.strip_prefix("BitFlags<DoneStatus>(0b")?;
+            let bits = bits.strip_suffix(')')?;
+            let bits = bits.split_once(", ").map_or(bits, |(bits, _)| bits);
+            let bits = u16::from_str_radix(bits, 2).ok()?;
+            let rows = match rows {
+                Some(rows) => rows
+                    .strip_suffix(" rows left)")
+                    .or_else(|| rows.strip_suffix(" row left)"))?
+                    .parse()
+                    .ok()?,
+                None => 0,
+            };
+            Some((bits & COUNT != 0).then_some(rows))
+        };
+        Some(read().ok_or_else(|| anyhow!("unreadable done token `{message}`")))
+    }
+
+    /// The message of an event that may be a done token. tiberius logs every
+    /// row it reads with the same target and level, so formatting stops at
+    /// the first bytes that do not start a done token rather than writing
+    /// out the whole row.
+    #[derive(Default)]
+    struct DoneField(String);
+
+    impl DoneField {
+        fn read(event: &Event<'_>) -> String {
+            let mut visitor = Self::default();
+            event.record(&mut visitor);
+            visitor.0
+        }
+    }
+
+    impl Visit for DoneField {
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            if field.name() == "message" && fmt::write(self, format_args!("{value:?}")).is_err() {
+                self.0.clear();
+            }
+        }
+    }
+
+    impl fmt::Write for DoneField {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            self.0 += s;
+            let len = self.0.len().min(DONE.len());
+            match self.0.as_bytes()[..len] == DONE.as_bytes()[..len] {
+                true => Ok(()),
+                false => Err(fmt::Error),
+            }
+        }
+    }
+
+    #[derive(Default)]
+    struct MetaField(String);
+
+    impl Visit for MetaField {
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            if field.name() == "meta" {
+                // one field to a line is easier to pick apart
+                self.0 = format!("{value:#?}");
+            }
+        }
+    }
+
+    /// What a column token tells beyond the column's name.
+    #[derive(Debug, Default, PartialEq, Eq)]
+    struct Details {
+        ty: Option<String>,
+        flags: Option<u16>,
+        len: Option<usize>,
+        precision: Option<u8>,
+        scale: Option<u8>,
+    }
+
+    /// Wrappers of the type in tiberius' `TypeInfo`.
+    const TYPE_INFO: &[&str] = &["FixedLen", "VarLenSized", "VarLenSizedPrecision"];
+
+    /// Picks the details of each column out of the pretty-printed
+    /// `TokenColMetaData` tiberius logs.
+    fn parse(token: &str) -> Vec<Details> {
+        let mut columns: Vec<Details> = Vec::new();
+        for line in token.lines() {
+            let line = line.trim().trim_end_matches(',');
+            if line == "MetaDataColumn {" {
+                columns.push(Details::default());
+                continue;
+            }
+            let Some(column) = columns.last_mut() else {
+                continue;
+            };
+            let (key, value) = line.split_once(": ").unwrap_or(("", line));
+            match key {
+                "bits" => {
+                    column.flags = u16::from_str_radix(value.trim_start_matches("0b"), 2).ok()
+                }
+                "len" => column.len = value.parse().ok(),
+                "precision" => column.precision = value.parse().ok(),
+                "scale" => column.scale = value.parse().ok(),
+                // the type itself is the first name not wrapping it, either
+                // as the value of `ty` or `type` or on a line of its own
+                "ty" | "type" if column.ty.is_none() => {
+                    let name = value.trim_end_matches(" {").trim_end_matches('(');
+                    column.ty = type_name(name)
+                        .filter(|name| !TYPE_INFO.contains(name))
+                        .map(str::to_string);
+                }
+                "" if column.ty.is_none() => column.ty = type_name(value).map(str::to_string),
+                _ => {}
+            }
+        }
+        columns
+    }
+
+    fn type_name(s: &str) -> Option<&str> {
+        let is_name = !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric());
+        is_name.then_some(s)
+    }
+
+    impl Details {
+        fn column(&self, column: &tiberius::Column) -> Column {
+            let ty = match &self.ty {
+                Some(ty) => ty.clone(),
+                None => format!("{:?}", column.column_type()),
+            };
+            let scale = match ty.as_str() {
+                "Decimaln" | "Numericn" => self.scale,
+                // the length of these is the digits of their fractional seconds
+                "Timen" | "Datetime2" | "DatetimeOffsetn" => {
+                    self.len.and_then(|n| n.try_into().ok())
+                }
+                _ => None,
+            };
+            Column {
+                name: column.name().to_string(),
+                sql_type: sql_type(&ty, self.len, self.precision, scale),
+                nullable: self.flags.map(|flags| flags & 1 != 0),
+                precision: self.precision,
+                scale,
+            }
+        }
+    }
+
+    /// Length tiberius gives the `(max)` types.
+    const MAX_LEN: usize = 0xFFFF;
+
+    /// Spells a TDS type the way SQL Server does.
+    fn sql_type(ty: &str, len: Option<usize>, precision: Option<u8>, scale: Option<u8>) -> String {
+        let sized = |name: &str, len: Option<usize>| match len {
+            Some(MAX_LEN) => format!("{name}(max)"),
+            Some(len) => format!("{name}({len})"),
+            None => name.to_string(),
+        };
+        // n-types hold UTF-16, their length is in bytes
+        let chars = len.map(|len| if len == MAX_LEN { len } else { len / 2 });
+        let name = match (ty, len) {
+            ("Int1", _) | ("Intn", Some(1)) => "tinyint",
+            ("Int2", _) | ("Intn", Some(2)) => "smallint",
+            ("Int8", _) | ("Intn", Some(8)) => "bigint",
+            ("Int4" | "Intn", _) => "int",
+            ("Bit" | "Bitn", _) => "bit",
+            ("Float4", _) | ("Floatn", Some(4)) => "real",
+            ("Float8" | "Floatn", _) => "float",
+            ("Money4", _) | ("Money", Some(4)) => "smallmoney",
+            ("Money", _) => "money",
+            ("Datetime4", _) | ("Datetimen", Some(4)) => "smalldatetime",
+            ("Datetime" | "Datetimen", _) => "datetime",
+            ("Daten", _) => "date",
+            ("Guid", _) => "uniqueidentifier",
+            ("Timen", _) => return sized("time", scale.map(usize::from)),
+            ("Datetime2", _) => return sized("datetime2", scale.map(usize::from)),
+            ("DatetimeOffsetn", _) => return sized("datetimeoffset", scale.map(usize::from)),
+            ("Decimaln" | "Numericn", _) => {
+                let name = if ty == "Decimaln" {
+                    "decimal"
                 } else {
-                    (b.as_ref(), "")
+                    "numeric"
                 };
-                for bi in b {
-                    write!(f, "{bi:x}")?
+                return match (precision, scale) {
+                    (Some(precision), Some(scale)) => format!("{name}({precision},{scale})"),
+                    _ => name.to_string(),
+                };
+            }
+            ("BigVarBin", _) => return sized("varbinary", len),
+            ("BigVarChar", _) => return sized("varchar", len),
+            ("BigBinary", _) => return sized("binary", len),
+            ("BigChar", _) => return sized("char", len),
+            ("NVarchar", _) => return sized("nvarchar", chars),
+            ("NChar", _) => return sized("nchar", chars),
+            ("Text", _) => "text",
+            ("NText", _) => "ntext",
+            ("Image", _) => "image",
+            ("Xml", _) => "xml",
+            ("SSVariant", _) => "sql_variant",
+            ("Udt", _) => "udt",
+            ("Null", _) => "null",
+            (other, _) => other,
+        };
+        name.to_string()
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        // captured from tiberius 0.12.3 for `SELECT id, name, price, created`
+        // from a table of `int NOT NULL`, `nvarchar(100)`, `decimal(10,2)` and
+        // `datetime2(3)` columns
+        const TOKEN: &str = r#"TokenColMetaData {
+    columns: [
+        MetaDataColumn {
+            base: BaseMetaDataColumn {
+                flags: BitFlags<ColumnFlag> {
+                    bits: 0b0,
+                },
+                ty: FixedLen(
+                    Int4,
+                ),
+            },
+            col_name: "id",
+        },
+        MetaDataColumn {
+            base: BaseMetaDataColumn {
+                flags: BitFlags<ColumnFlag> {
+                    bits: 0b1001,
+                    flags: Nullable | Updateable,
+                },
+                ty: VarLenSized(
+                    VarLenContext {
+                        type: NVarchar,
+                        len: 200,
+                        collation: Some(
+                            Collation {
+                                info: 13632521,
+                                sort_id: 52,
+                            },
+                        ),
+                    },
+                ),
+            },
+            col_name: "name",
+        },
+        MetaDataColumn {
+            base: BaseMetaDataColumn {
+                flags: BitFlags<ColumnFlag> {
+                    bits: 0b1,
+                    flags: Nullable,
+                },
+                ty: VarLenSizedPrecision {
+                    ty: Decimaln,
+                    size: 9,
+                    precision: 10,
+                    scale: 2,
+                },
+            },
+            col_name: "price",
+        },
+        MetaDataColumn {
+            base: BaseMetaDataColumn {
+                flags: BitFlags<ColumnFlag> {
+                    bits: 0b1,
+                    flags: Nullable,
+                },
+                ty: VarLenSized(
+                    VarLenContext {
+                        type: Datetime2,
+                        len: 3,
+                        collation: None,
+                    },
+                ),
+            },
+            col_name: "created",
+        },
+    ],
+}"#;
+
+        #[test]
+        fn column_token() {
+            let details = |ty: &str, flags, len, precision, scale| Details {
+                ty: Some(ty.to_string()),
+                flags: Some(flags),
+                len,
+                precision,
+                scale,
+            };
+            assert_eq!(
+                parse(TOKEN),
+                [
+                    details("Int4", 0, None, None, None),
+                    details("NVarchar", 0b1001, Some(200), None, None),
+                    details("Decimaln", 0b1, None, Some(10), Some(2)),
+                    details("Datetime2", 0b1, Some(3), None, None),
+                ]
+            );
+        }
+
+        #[test]
+        fn done_tokens() {
+            // as tiberius 0.12.3 logs them
+            let done = |status: &str| {
+                done_token(&format!("Done with status {status}")).map(|done| done.ok())
+            };
+            let counted = "BitFlags<DoneStatus>(0b10001, More | Count) (3 rows left)";
+            assert_eq!(done(counted), Some(Some(Some(3))));
+            let counted = "BitFlags<DoneStatus>(0b10000, Count) (1 row left)";
+            assert_eq!(done(counted), Some(Some(Some(1))));
+            let counted = "BitFlags<DoneStatus>(0b10000, Count)";
+            assert_eq!(done(counted), Some(Some(Some(0))));
+            assert_eq!(done("BitFlags<DoneStatus>(0b1, More)"), Some(Some(None)));
+            assert_eq!(done("BitFlags<DoneStatus>(0b0)"), Some(Some(None)));
+            assert!(done_token("Database change from a to b").is_none());
+            // written some other way than by the pinned versions
+            for status in [
+                "DoneStatus(More | Count) (3 rows left)",
+                "BitFlags<DoneStatus>(0x11, More | Count)",
+                "BitFlags<DoneStatus>(0b10001, More | Count) (3 left)",
+                "BitFlags<DoneStatus>(0b10001, More | Count) [3 rows left]",
+            ] {
+                assert_eq!(done(status), Some(None), "{status}");
+            }
+        }
+
+        #[test]
+        fn records_column_tokens() {
+            let recorder = Recorder::install();
+            tracing::event!(target: TOKEN_TARGET, Level::TRACE, meta = ?"first");
+            tracing::event!(target: TOKEN_TARGET, Level::TRACE, other = ?"ignored");
+            tracing::event!(target: TOKEN_TARGET, Level::TRACE, meta = ?"second");
+            assert_eq!(recorder.log().tokens, ["\"first\"", "\"second\""]);
+        }
+
+        #[test]
+        fn records_counts_and_messages() -> Result<()> {
+            let done = |rows: &str| {
+                let status = "BitFlags<DoneStatus>(0b10001, More | Count)";
+                tracing::event!(target: TOKEN_TARGET, Level::TRACE, "Done with status {status}{rows}");
+            };
+            let recorder = Recorder::install();
+            done(" (3 rows left)");
+            tracing::event!(target: TOKEN_TARGET, Level::INFO, "{}", "Database change from a to b");
+            tracing::event!(target: TOKEN_TARGET, Level::INFO, "{}", "step 1 done");
+            tracing::event!(target: TOKEN_TARGET, Level::TRACE, meta = ?"set");
+            // the rows of the result set
+            done(" (1 row left)");
+            done("");
+            tracing::event!(
+                target: TOKEN_TARGET,
+                Level::TRACE,
+                "Done with status BitFlags<DoneStatus>(0b0)"
+            );
+            assert_eq!(recorder.rows_affected()?, [3, 0]);
+            assert_eq!(recorder.messages(), ["step 1 done"]);
+
+            tracing::event!(target: TOKEN_TARGET, Level::TRACE, "Done with status 17");
+            let e = recorder.rows_affected().err().map(|e| e.to_string());
+            assert_eq!(
+                e.as_deref(),
+                Some("cannot count rows affected: unreadable done token `Done with status 17`")
+            );
+            Ok(())
+        }
+
+        #[test]
+        fn rows_not_formatted() -> Result<()> {
+            use std::sync::atomic::{AtomicUsize, Ordering};
+
+            static FORMATTED: AtomicUsize = AtomicUsize::new(0);
+            struct Value;
+            impl fmt::Debug for Value {
+                fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                    FORMATTED.fetch_add(1, Ordering::Relaxed);
+                    f.write_str("Value")
                 }
-                write!(f, "{postfix}")
             }
-            None => fmt_null(f),
+            #[derive(Debug)]
+            struct TokenRow {
+                data: Vec<Value>,
+            }
+
+            let recorder = Recorder::install();
+            let row = TokenRow {
+                data: vec![Value, Value],
+            };
+            for _ in 0..5 {
+                // as tiberius logs each row it reads
+                tracing::event!(target: TOKEN_TARGET, Level::TRACE, message = ?row);
+            }
+            assert_eq!(FORMATTED.load(Ordering::Relaxed), 0);
+            let status = "BitFlags<DoneStatus>(0b10000, Count) (2 rows left)";
+            tracing::event!(target: TOKEN_TARGET, Level::TRACE, "Done with status {status}");
+            assert_eq!(recorder.rows_affected()?, [2]);
+            Ok(())
+        }
+
+        #[test]
+        fn sql_types() {
+            assert_eq!(
+                sql_type("NVarchar", Some(MAX_LEN), None, None),
+                "nvarchar(max)"
+            );
+            assert_eq!(sql_type("BigVarChar", Some(20), None, None), "varchar(20)");
+            assert_eq!(
+                sql_type("Datetime2", Some(7), None, Some(7)),
+                "datetime2(7)"
+            );
+            assert_eq!(sql_type("Floatn", Some(4), None, None), "real");
+            assert_eq!(sql_type("Numericn", None, None, None), "numeric");
         }
     }
 }
@@ -324,53 +1357,250 @@ pub(crate) mod json {
 
     use serde::ser::SerializeMap;
 
-    use super::{ResultRow, ResultValueRef};
+    use super::{fmt, ResultRow, ResultValueRef};
+    use crate::{cli, fmt_util};
+
+    /// Largest integer a JSON reader using doubles, such as JavaScript,
+    /// reads back exactly.
+    const MAX_SAFE_INTEGER: u64 = (1 << 53) - 1;
+
+    /// A row or value serialized as `--json-types` and `--binary` tell.
+    pub(crate) struct Json<'o, T> {
+        value: T,
+        options: &'o cli::JsonOptions,
+        binary: cli::Binary,
+    }
+
+    impl ResultRow {
+        pub(crate) fn json<'o>(
+            &self,
+            options: &'o cli::JsonOptions,
+            binary: cli::Binary,
+        ) -> Json<'o, &Self> {
+            Json {
+                value: self,
+                options,
+                binary,
+            }
+        }
+    }
 
-    impl serde::Serialize for ResultRow {
+    impl serde::Serialize for Json<'_, &ResultRow> {
         fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
         where
             S: serde::Serializer,
         {
-            let mut row = serializer.serialize_map(Some(self.0.len()))?;
-            for (i, (col, val)) in self.iter_columns().zip(self.iter_values()).enumerate() {
+            type C<'a> = tiberius::ColumnData<'a>;
+            let row = self.value;
+            // `--binary omit` leaves out the keys of binary columns
+            let omitted = |value: &ResultValueRef| {
+                self.binary == cli::Binary::Omit && matches!(value.0, C::Binary(_))
+            };
+            let len = row.iter_values().filter(|value| !omitted(value)).count();
+            let mut map = serializer.serialize_map(Some(len))?;
+            for (i, (col, value)) in row.iter_columns().zip(row.iter_values()).enumerate() {
+                if omitted(&value) {
+                    continue;
+                }
+                let value = Json {
+                    value,
+                    options: self.options,
+                    binary: self.binary,
+                };
                 if col.is_empty() {
-                    row.serialize_key(&format_args!("_{i}"))?;
-                    row.serialize_value(&val)?;
+                    map.serialize_key(&format_args!("_{i}"))?;
+                    map.serialize_value(&value)?;
                 } else {
-                    row.serialize_entry(col, &val)?;
+                    map.serialize_entry(col, &value)?;
                 }
             }
-            row.end()
+            map.end()
         }
     }
 
-    impl<'a, 'b> serde::Serialize for ResultValueRef<'a, 'b> {
+    impl serde::Serialize for Json<'_, ResultValueRef<'_, '_>> {
         fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
         where
             S: serde::Serializer,
         {
             type C<'a> = tiberius::ColumnData<'a>;
-            match self.0 {
-                C::Binary(Some(_))
-                | C::Date(Some(_))
-                | C::DateTime(Some(_))
-                | C::DateTime2(Some(_))
-                | C::DateTimeOffset(Some(_))
-                | C::Guid(Some(_))
-                | C::Numeric(Some(_))
-                | C::SmallDateTime(Some(_))
-                | C::String(Some(_))
-                | C::Time(Some(_))
-                | C::Xml(Some(_)) => serializer.collect_str(&self),
-                C::Bit(Some(b)) => serializer.serialize_bool(*b),
-                C::F32(Some(f)) => serializer.serialize_f32(*f),
-                C::F64(Some(f)) => serializer.serialize_f64(*f),
-                C::I16(Some(i)) => serializer.serialize_i16(*i),
-                C::I32(Some(i)) => serializer.serialize_i32(*i),
-                C::I64(Some(i)) => serializer.serialize_i64(*i),
-                C::U8(Some(i)) => serializer.serialize_u8(*i),
-                _ => serializer.serialize_none(),
+            let value = &self.value;
+            let types = self.options.json_types;
+            if value.is_null() {
+                return serializer.serialize_none();
+            }
+            // the rest are strings, or under `tagged` objects naming their type
+            let (tag, text) = match value.0 {
+                C::Bit(Some(b)) => return serializer.serialize_bool(*b),
+                C::U8(Some(i)) => return serializer.serialize_u8(*i),
+                C::I16(Some(i)) => return serializer.serialize_i16(*i),
+                C::I32(Some(i)) => return serializer.serialize_i32(*i),
+                C::I64(Some(i)) => match types {
+                    cli::JsonTypes::Native => return serializer.serialize_i64(*i),
+                    cli::JsonTypes::Safe if i.unsigned_abs() <= MAX_SAFE_INTEGER => {
+                        return serializer.serialize_i64(*i)
+                    }
+                    _ => ("$bigint", i.to_string()),
+                },
+                C::F32(Some(f)) => return serializer.serialize_f32(*f),
+                C::F64(Some(f)) => return serializer.serialize_f64(*f),
+                C::Numeric(Some(n)) => ("$decimal", fmt_util::decimal(*n)),
+                C::Binary(Some(b)) => ("$binary", fmt::binary(b, self.binary)),
+                C::Guid(Some(_)) => ("$guid", value.to_string()),
+                C::Date(Some(_)) => ("$date", value.to_string()),
+                C::Time(Some(_)) => ("$time", value.to_string()),
+                C::DateTime(Some(_)) | C::SmallDateTime(Some(_)) | C::DateTime2(Some(_)) => {
+                    ("$datetime", value.to_string())
+                }
+                C::DateTimeOffset(Some(_)) => ("$datetimeoffset", value.to_string()),
+                _ => return serializer.collect_str(value),
+            };
+            match types {
+                cli::JsonTypes::Tagged => {
+                    let mut map = serializer.serialize_map(Some(1))?;
+                    map.serialize_entry(tag, &text)?;
+                    map.end()
+                }
+                _ => serializer.serialize_str(&text),
             }
         }
     }
+
+    #[cfg(test)]
+    mod tests {
+        use tiberius::{numeric::Numeric, ColumnData as C};
+
+        use super::*;
+
+        type Result<T> = std::result::Result<T, anyhow::Error>;
+
+        fn json(value: C<'_>, json_types: cli::JsonTypes, binary: cli::Binary) -> Result<String> {
+            let options = cli::JsonOptions {
+                set_markers: false,
+                json_envelope: false,
+                json_types,
+            };
+            let value = Json {
+                value: ResultValueRef(&value),
+                options: &options,
+                binary,
+            };
+            Ok(serde_json::to_string(&value)?)
+        }
+
+        #[test]
+        fn json_types() -> Result<()> {
+            use cli::{Binary::*, JsonTypes::*};
+            let big = || C::I64(Some(9_007_199_254_740_993));
+            assert_eq!(json(big(), Native, Hex)?, "9007199254740993");
+            assert_eq!(json(big(), Safe, Hex)?, "\"9007199254740993\"");
+            assert_eq!(json(C::I64(Some(-42)), Safe, Hex)?, "-42");
+            let safe = |i: i64| json(C::I64(Some(i)), Safe, Hex);
+            assert_eq!(safe((1 << 53) - 1)?, "9007199254740991");
+            assert_eq!(safe(1 - (1 << 53))?, "-9007199254740991");
+            assert_eq!(safe(1 << 53)?, "\"9007199254740992\"");
+            assert_eq!(safe(-(1 << 53))?, "\"-9007199254740992\"");
+            assert_eq!(safe(i64::MIN)?, "\"-9223372036854775808\"");
+            assert_eq!(
+                json(big(), Tagged, Hex)?,
+                r#"{"$bigint":"9007199254740993"}"#
+            );
+
+            let decimal = || C::Numeric(Some(Numeric::new_with_scale(-110, 2)));
+            assert_eq!(json(decimal(), Native, Hex)?, "\"-1.10\"");
+            assert_eq!(json(decimal(), Tagged, Hex)?, r#"{"$decimal":"-1.10"}"#);
+
+            let binary = || C::Binary(Some(vec![0x0a, 0xff].into()));
+            assert_eq!(json(binary(), Native, Hex)?, "\"0aff\"");
+            assert_eq!(json(binary(), Safe, Base64)?, "\"Cv8=\"");
+            assert_eq!(json(binary(), Tagged, Base64)?, r#"{"$binary":"Cv8="}"#);
+            assert_eq!(json(binary(), Native, Prefixed)?, "\"0x0AFF\"");
+
+            assert_eq!(json(C::I32(Some(7)), Tagged, Hex)?, "7");
+            assert_eq!(json(C::String(Some("a".into())), Tagged, Hex)?, "\"a\"");
+            assert_eq!(json(C::Numeric(None), Tagged, Hex)?, "null");
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use anyhow::anyhow;
+    use async_std::task::block_on;
+
+    use super::*;
+
+    /// Records the batches run, failing those `fails` says to in turn.
+    #[derive(Default)]
+    struct Fake {
+        sent: Vec<String>,
+        fails: VecDeque<bool>,
+    }
+
+    impl Batch for Fake {
+        async fn batch(&mut self, sql: &str) -> Result<()> {
+            self.sent.push(sql.to_string());
+            match self.fails.pop_front().unwrap_or(false) {
+                true => Err(anyhow!("error {}", self.sent.len())),
+                false => Ok(()),
+            }
+        }
+    }
+
+    fn fake(fails: &[bool]) -> Fake {
+        Fake {
+            sent: Vec::new(),
+            fails: fails.iter().copied().collect(),
+        }
+    }
+
+    #[test]
+    fn transaction() -> Result<()> {
+        let mut tx = Transaction::default();
+        let mut conn = fake(&[]);
+        block_on(tx.begin(&mut conn))?;
+        assert!(block_on(tx.begin(&mut conn)).is_err());
+        block_on(tx.commit(&mut conn))?;
+        assert!(!tx.0);
+        assert!(block_on(tx.commit(&mut conn)).is_err());
+        assert!(block_on(tx.rollback(&mut conn)).is_err());
+        assert_eq!(conn.sent, ["BEGIN TRANSACTION", "COMMIT TRANSACTION"]);
+
+        // a failed begin leaves no transaction open
+        let mut conn = fake(&[true]);
+        assert!(block_on(tx.begin(&mut conn)).is_err());
+        assert!(!tx.0);
+        Ok(())
+    }
+
+    #[test]
+    fn failed_commit() -> Result<()> {
+        let rollback = "IF @@TRANCOUNT > 0 ROLLBACK TRANSACTION";
+        let error = |res: Result<()>| res.err().map(|e| format!("{e:#}")).unwrap_or_default();
+
+        let mut tx = Transaction(true);
+        let mut conn = fake(&[true]);
+        let e = error(block_on(tx.commit(&mut conn)));
+        assert_eq!(e, "commit failed, transaction rolled back: error 1");
+        assert!(!tx.0);
+        assert_eq!(conn.sent, ["COMMIT TRANSACTION", rollback]);
+
+        // still open on the server if that fails too, so still open here
+        let mut tx = Transaction(true);
+        let mut conn = fake(&[true, true]);
+        let e = error(block_on(tx.commit(&mut conn)));
+        assert_eq!(e, "commit failed, transaction still open: error 1");
+        assert!(tx.0);
+        block_on(tx.rollback(&mut conn))?;
+        assert!(!tx.0);
+
+        let mut tx = Transaction(true);
+        let mut conn = fake(&[true]);
+        assert!(block_on(tx.rollback(&mut conn)).is_err());
+        assert!(tx.0);
+        Ok(())
+    }
 }
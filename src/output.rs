@@ -0,0 +1,690 @@
+use std::{
+    ffi::{OsStr, OsString},
+    fs::{File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+
+use crate::{
+    cli,
+    fmt_util::{Format, Join},
+    mssql,
+};
+
+type Result<T> = std::result::Result<T, anyhow::Error>;
+
+/// Settles the format of the results, given by `-f` or else told by the
+/// extension of the `-o` file, and the table `-f sql` inserts into.
+pub(crate) fn resolve_format(args: &mut cli::ArgsQuery) -> Result<()> {
+    let path = args
+        .output
+        .as_deref()
+        .filter(|path| *path != "-")
+        .map(Path::new);
+    let extension = path
+        .and_then(Path::extension)
+        .and_then(OsStr::to_str)
+        .map(str::to_ascii_lowercase);
+    let format = args.format.get_or_insert(match extension.as_deref() {
+        Some("json") => cli::OutputFormat::Json,
+        Some("ndjson" | "jsonl") => cli::OutputFormat::Ndjson,
+        Some("md" | "markdown") => cli::OutputFormat::Markdown,
+        Some("txt") => cli::OutputFormat::Text,
+        Some("sql") => cli::OutputFormat::Sql,
+        Some("csv") => cli::OutputFormat::Csv,
+        _ if args.json.json_envelope => cli::OutputFormat::Json,
+        _ => cli::OutputFormat::Csv,
+    });
+    if args.json.json_envelope && !matches!(format, cli::OutputFormat::Json) {
+        bail!("--json-envelope only goes with -f json");
+    }
+    if matches!(format, cli::OutputFormat::Sql) && args.insert_into.is_none() {
+        let Some(name) = path.and_then(Path::file_stem).and_then(OsStr::to_str) else {
+            bail!("give the table to insert into with --table");
+        };
+        args.insert_into = Some(quote_name(name));
+    }
+    Ok(())
+}
+
+/// Whether server messages are part of the results, rather than written on
+/// their own.
+pub(crate) fn keeps_messages(args: &cli::ArgsQuery) -> bool {
+    args.json.json_envelope && args.messages.is_none()
+}
+
+/// Writes each result set as its rows arrive from the server, except for
+/// tables which need all the rows of a set to line up its columns.
+pub(crate) async fn write_results(
+    mut results: mssql::QueryResults<'_>,
+    format: &cli::OutputFormat,
+    args: &cli::ArgsQuery,
+    out: &mut impl Write,
+) -> Result<()> {
+    if matches!(format, cli::OutputFormat::Json) && args.json.json_envelope {
+        return write_envelope(results, args, out).await;
+    }
+    let csv_options = &args.csv;
+    if matches!(format, cli::OutputFormat::Csv) && csv_options.bom {
+        out.write_all("\u{feff}".as_bytes())?;
+    }
+    let mut set_idx = 0;
+    while let Some(mut result_set) = results.next_set().await? {
+        set_idx += 1;
+        match format {
+            cli::OutputFormat::Csv => {
+                // result sets are told apart by a blank line, each has its own header
+                if set_idx > 1 {
+                    out.write_all(csv::line_ending(csv_options))?;
+                }
+                let mut writer = csv::writer(csv_options, &mut *out)?;
+                if !csv_options.no_header {
+                    let names = result_set.columns().iter().map(|c| &c.name);
+                    writer.write_record(names).map_err(io::Error::from)?;
+                }
+                while let Some(row) = result_set.next_row().await? {
+                    let fields = row.iter_values().map(|val| match val.is_null() {
+                        true => csv_options.null.clone(),
+                        false => val.text(args.binary).to_string(),
+                    });
+                    writer.write_record(fields).map_err(io::Error::from)?;
+                }
+                writer.flush()?;
+            }
+            cli::OutputFormat::Json => {
+                write!(out, "[")?;
+                let mut first = true;
+                while let Some(row) = result_set.next_row().await? {
+                    if !std::mem::take(&mut first) {
+                        write!(out, ",")?;
+                    }
+                    json(out, &row.json(&args.json, args.binary))?;
+                }
+                writeln!(out, "]")?;
+            }
+            cli::OutputFormat::Ndjson => {
+                if args.json.set_markers {
+                    let names: Vec<_> = result_set.columns().iter().map(|c| &c.name).collect();
+                    let marker = serde_json::json!({ "$result_set": set_idx, "columns": names });
+                    json(out, &marker)?;
+                    writeln!(out)?;
+                }
+                while let Some(row) = result_set.next_row().await? {
+                    json(out, &row.json(&args.json, args.binary))?;
+                    writeln!(out)?;
+                    // whoever reads the lines gets each row as it arrives
+                    out.flush()?;
+                }
+            }
+            cli::OutputFormat::Sql => {
+                let Some(table) = &args.insert_into else {
+                    bail!("give the table to insert into with --table");
+                };
+                let names = result_set.columns().iter().map(|c| quote_name(&c.name));
+                let insert = format!("INSERT INTO {table} ({})", names.join(", "));
+                while let Some(row) = result_set.next_row().await? {
+                    let values = row
+                        .iter_values()
+                        .map(|val| val.to_param().to_literal())
+                        .collect::<Result<Vec<_>>>()?;
+                    writeln!(out, "{insert} VALUES ({});", values.iter().join(", "))?;
+                }
+            }
+            cli::OutputFormat::Text | cli::OutputFormat::Markdown => {
+                if set_idx > 1 {
+                    writeln!(out)?;
+                }
+                let style = match format {
+                    cli::OutputFormat::Markdown => table::Style::Markdown,
+                    _ => table::Style::Boxed,
+                };
+                let mut table = table::Table::new(result_set.columns(), style);
+                while let Some(row) = result_set.next_row().await? {
+                    table.push(&row, args.table.max_width, args.binary);
+                }
+                table.write(out)?;
+            }
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Writes the results as one JSON document, the rows of each result set
+/// still written as they arrive:
+///
+/// ```json
+/// {"result_sets":[{"index":1,"columns":[...],"rows":[...],"row_count":2}],
+///  "rows_affected":[3],"messages":["..."],"elapsed_ms":12}
+/// ```
+async fn write_envelope(
+    mut results: mssql::QueryResults<'_>,
+    args: &cli::ArgsQuery,
+    out: &mut impl Write,
+) -> Result<()> {
+    let mut envelope = Envelope::start(out)?;
+    while let Some(mut result_set) = results.next_set().await? {
+        envelope.set(result_set.columns())?;
+        while let Some(row) = result_set.next_row().await? {
+            envelope.row(&row.json(&args.json, args.binary))?;
+        }
+        envelope.end_set()?;
+    }
+    envelope.finish(
+        &results.rows_affected()?,
+        &results.messages(),
+        results.elapsed(),
+    )?;
+    Ok(())
+}
+
+/// The `--json-envelope` document, written a piece at a time as the results
+/// arrive: `{"result_sets":[{"index":1,"columns":[...],"rows":[...],
+/// "row_count":2}],"rows_affected":[...],"messages":[...],"elapsed_ms":5}`.
+struct Envelope<'o, W: Write> {
+    out: &'o mut W,
+    sets: usize,
+    rows: usize,
+}
+
+impl<'o, W: Write> Envelope<'o, W> {
+    fn start(out: &'o mut W) -> io::Result<Self> {
+        write!(out, "{{\"result_sets\":[")?;
+        Ok(Self {
+            out,
+            sets: 0,
+            rows: 0,
+        })
+    }
+
+    fn set(&mut self, columns: &[mssql::metadata::Column]) -> io::Result<()> {
+        self.sets += 1;
+        self.rows = 0;
+        if self.sets > 1 {
+            write!(self.out, ",")?;
+        }
+        write!(self.out, "{{\"index\":{},\"columns\":", self.sets)?;
+        json(self.out, columns)?;
+        write!(self.out, ",\"rows\":[")
+    }
+
+    fn row(&mut self, row: &impl serde::Serialize) -> io::Result<()> {
+        if self.rows > 0 {
+            write!(self.out, ",")?;
+        }
+        self.rows += 1;
+        json(self.out, row)
+    }
+
+    fn end_set(&mut self) -> io::Result<()> {
+        write!(self.out, "],\"row_count\":{}}}", self.rows)
+    }
+
+    fn finish(
+        self,
+        rows_affected: &[u64],
+        messages: &[String],
+        elapsed: Duration,
+    ) -> io::Result<()> {
+        write!(self.out, "],\"rows_affected\":")?;
+        json(self.out, rows_affected)?;
+        write!(self.out, ",\"messages\":")?;
+        json(self.out, messages)?;
+        writeln!(self.out, ",\"elapsed_ms\":{}}}", elapsed.as_millis())?;
+        self.out.flush()
+    }
+}
+
+fn json(out: &mut impl Write, value: &(impl serde::Serialize + ?Sized)) -> io::Result<()> {
+    // kept an io::Error so a closed pipe is still recognised
+    serde_json::to_writer(out, value).map_err(io::Error::from)
+}
+
+/// `name` as a bracketed identifier.
+fn quote_name(name: &str) -> String {
+    format!("[{}]", name.replace(']', "]]"))
+}
+
+/// A file written under a temporary name next to where it belongs, and
+/// renamed into place by [`AtomicFile::commit`]. Until then a file already
+/// at the path is left as it was, and the temporary file is removed if the
+/// writing does not get that far.
+pub(crate) struct AtomicFile {
+    file: BufWriter<File>,
+    temp: PathBuf,
+    path: PathBuf,
+    committed: bool,
+}
+
+impl AtomicFile {
+    pub(crate) fn create(path: &str) -> Result<Self> {
+        let path = PathBuf::from(path);
+        let Some(name) = path.file_name() else {
+            bail!("`{}` is not a file name", path.display());
+        };
+        let mut temp = OsString::from(".");
+        temp.push(name);
+        temp.push(format!(".{}.tmp", std::process::id()));
+        let temp = path.with_file_name(temp);
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&temp)
+            .with_context(|| format!("creating `{}`", temp.display()))?;
+        Ok(Self {
+            file: BufWriter::new(file),
+            temp,
+            path,
+            committed: false,
+        })
+    }
+
+    /// Puts the file in place, now that all of it has been written.
+    pub(crate) fn commit(mut self) -> Result<()> {
+        self.file.flush()?;
+        self.file.get_ref().sync_all()?;
+        std::fs::rename(&self.temp, &self.path)
+            .with_context(|| format!("writing `{}`", self.path.display()))?;
+        self.committed = true;
+        Ok(())
+    }
+}
+
+impl Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = std::fs::remove_file(&self.temp);
+        }
+    }
+}
+
+mod csv {
+    use super::*;
+
+    /// A writer of one result set in the dialect asked for, quoting fields
+    /// as RFC 4180 has it.
+    pub(super) fn writer<W: Write>(options: &cli::CsvOptions, out: W) -> Result<::csv::Writer<W>> {
+        let terminator = match options.line_ending {
+            cli::LineEnding::Lf => ::csv::Terminator::Any(b'\n'),
+            cli::LineEnding::Crlf => ::csv::Terminator::CRLF,
+        };
+        Ok(::csv::WriterBuilder::new()
+            .delimiter(byte("--delimiter", &options.delimiter)?)
+            .quote(byte("--quote", &options.quote)?)
+            .terminator(terminator)
+            .from_writer(out))
+    }
+
+    pub(super) fn line_ending(options: &cli::CsvOptions) -> &'static [u8] {
+        match options.line_ending {
+            cli::LineEnding::Lf => b"\n",
+            cli::LineEnding::Crlf => b"\r\n",
+        }
+    }
+
+    fn byte(option: &str, value: &str) -> Result<u8> {
+        match value {
+            "\\t" => Ok(b'\t'),
+            _ => match value.as_bytes() {
+                [byte] if byte.is_ascii() => Ok(*byte),
+                _ => bail!("{option} takes a single ASCII character, not `{value}`"),
+            },
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn options(args: &[&str]) -> cli::CsvOptions {
+            use clap::Parser;
+            #[derive(clap::Parser)]
+            struct Args {
+                #[command(flatten)]
+                csv: cli::CsvOptions,
+            }
+            Args::parse_from(std::iter::once("db").chain(args.iter().copied())).csv
+        }
+
+        fn write(options: &cli::CsvOptions, rows: &[&[&str]]) -> Result<String> {
+            let mut out = Vec::new();
+            let mut writer = writer(options, &mut out)?;
+            for row in rows {
+                writer.write_record(*row)?;
+            }
+            drop(writer);
+            Ok(String::from_utf8(out)?)
+        }
+
+        #[test]
+        fn dialects() -> Result<()> {
+            let rows: &[&[&str]] = &[&["id", "name"], &["1", "Porter, \"Phil\""], &["2", ""]];
+            assert_eq!(
+                write(&options(&[]), rows)?,
+                "id,name\n1,\"Porter, \"\"Phil\"\"\"\n2,\n"
+            );
+            assert_eq!(
+                write(
+                    &options(&["--delimiter", "\\t", "--line-ending", "crlf"]),
+                    rows
+                )?,
+                "id\tname\r\n1\t\"Porter, \"\"Phil\"\"\"\r\n2\t\r\n"
+            );
+            assert_eq!(
+                write(&options(&["--delimiter", ";", "--quote", "'"]), rows)?,
+                "id;name\n1;Porter, \"Phil\"\n2;\n"
+            );
+            assert!(writer(&options(&["--delimiter", "ab"]), Vec::new()).is_err());
+            Ok(())
+        }
+    }
+}
+
+mod table {
+    use std::fmt;
+
+    use super::*;
+    use crate::fmt_util;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub(super) enum Style {
+        /// Drawn with box characters, for reading on a terminal
+        Boxed,
+        /// A GitHub flavored markdown table
+        Markdown,
+    }
+
+    /// A result set laid out in columns as wide as their widest value.
+    pub(super) struct Table {
+        style: Style,
+        header: Vec<String>,
+        /// Whether each column is aligned to the right, as numbers are.
+        right: Vec<bool>,
+        widths: Vec<usize>,
+        rows: Vec<Vec<String>>,
+    }
+
+    impl Table {
+        pub(super) fn new(columns: &[mssql::metadata::Column], style: Style) -> Self {
+            let header: Vec<_> = columns.iter().map(|c| cell(&c.name, style)).collect();
+            // a markdown delimiter row needs at least three dashes
+            let min_width = match style {
+                Style::Boxed => 0,
+                Style::Markdown => 3,
+            };
+            Self {
+                style,
+                widths: header
+                    .iter()
+                    .map(|name| fmt_util::width(name).max(min_width))
+                    .collect(),
+                right: columns.iter().map(|c| c.is_numeric()).collect(),
+                header,
+                rows: Vec::new(),
+            }
+        }
+
+        /// Adds a row, cutting text and binary longer than `max_width` short.
+        pub(super) fn push(
+            &mut self,
+            row: &mssql::ResultRow,
+            max_width: Option<usize>,
+            binary: cli::Binary,
+        ) {
+            let cells = row
+                .iter_values()
+                .map(|val| val.text(binary))
+                .map(|val| match max_width {
+                    Some(max_width) => format!("{val:max_width$}"),
+                    None => val.to_string(),
+                })
+                .map(|text| cell(&text, self.style))
+                .collect();
+            self.push_cells(cells);
+        }
+
+        fn push_cells(&mut self, cells: Vec<String>) {
+            for (width, cell) in self.widths.iter_mut().zip(&cells) {
+                *width = (*width).max(fmt_util::width(cell));
+            }
+            self.rows.push(cells);
+        }
+
+        pub(super) fn write(&self, out: &mut impl Write) -> io::Result<()> {
+            match self.style {
+                Style::Boxed => {
+                    let rule = |sep| self.widths.iter().map(|w| "─".repeat(w + 2)).join(sep);
+                    writeln!(out, "┌{}┐", rule("┬"))?;
+                    writeln!(out, "│ {} │", self.line(&self.header, " │ "))?;
+                    writeln!(out, "├{}┤", rule("┼"))?;
+                    for row in &self.rows {
+                        writeln!(out, "│ {} │", self.line(row, " │ "))?;
+                    }
+                    writeln!(out, "└{}┘", rule("┴"))?;
+                }
+                Style::Markdown => {
+                    let delimiters =
+                        self.widths
+                            .iter()
+                            .zip(&self.right)
+                            .map(|(w, right)| match right {
+                                true => format!("{}:", "-".repeat(w + 1)),
+                                false => "-".repeat(w + 2),
+                            });
+                    writeln!(out, "| {} |", self.line(&self.header, " | "))?;
+                    writeln!(out, "|{}|", delimiters.join("|"))?;
+                    for row in &self.rows {
+                        writeln!(out, "| {} |", self.line(row, " | "))?;
+                    }
+                }
+            }
+            Ok(())
+        }
+
+        /// The cells of a row, each padded to the width of its column.
+        fn line<'a>(&'a self, cells: &'a [String], sep: &'a str) -> impl fmt::Display + 'a {
+            let pad = |cell: &((&String, &usize), &bool), f: &mut fmt::Formatter<'_>| {
+                let ((cell, width), right) = *cell;
+                let fill = " ".repeat(width.saturating_sub(fmt_util::width(cell)));
+                match right {
+                    true => write!(f, "{fill}{cell}"),
+                    false => write!(f, "{cell}{fill}"),
+                }
+            };
+            cells
+                .iter()
+                .zip(&self.widths)
+                .zip(&self.right)
+                .format(pad)
+                .join(sep)
+        }
+    }
+
+    /// Keeps a value on one line, and from being read as markdown.
+    fn cell(text: &str, style: Style) -> String {
+        let text = text.replace("\r\n", "\n");
+        match style {
+            Style::Boxed => text.replace('\n', "↵").replace('\t', " "),
+            Style::Markdown => text.replace('|', "\\|").replace('\n', "<br>"),
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn table(style: Style) -> Table {
+            let column = |name: &str, sql_type: &str| mssql::metadata::Column {
+                name: name.to_string(),
+                sql_type: sql_type.to_string(),
+                nullable: Some(false),
+                precision: None,
+                scale: None,
+            };
+            let mut table = Table::new(
+                &[column("id", "int"), column("name", "nvarchar(50)")],
+                style,
+            );
+            table.push_cells(vec!["1".to_string(), cell("Jürgen | 日本", style)]);
+            table.push_cells(vec!["10".to_string(), cell("two\nlines", style)]);
+            table
+        }
+
+        fn write(table: &Table) -> Result<String> {
+            let mut out = Vec::new();
+            table.write(&mut out)?;
+            Ok(String::from_utf8(out)?)
+        }
+
+        #[test]
+        fn boxed() -> Result<()> {
+            assert_eq!(
+                write(&table(Style::Boxed))?,
+                "\
+┌────┬───────────────┐
+│ id │ name          │
+├────┼───────────────┤
+│  1 │ Jürgen | 日本 │
+│ 10 │ two↵lines     │
+└────┴───────────────┘
+"
+            );
+            Ok(())
+        }
+
+        #[test]
+        fn markdown() -> Result<()> {
+            assert_eq!(
+                write(&table(Style::Markdown))?,
+                "\
+|  id | name           |
+|----:|----------------|
+|   1 | Jürgen \\| 日本 |
+|  10 | two<br>lines   |
+"
+            );
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolved(args: &[&str]) -> Result<cli::ArgsQuery> {
+        use clap::Parser;
+        let mut args =
+            cli::ArgsQuery::parse_from(std::iter::once("query").chain(args.iter().copied()));
+        resolve_format(&mut args)?;
+        Ok(args)
+    }
+
+    #[test]
+    fn format_from_extension() -> Result<()> {
+        let args = resolved(&["-o", "out/users.JSON"])?;
+        assert!(matches!(args.format, Some(cli::OutputFormat::Json)));
+        let args = resolved(&["-o", "users.md", "-f", "csv"])?;
+        assert!(matches!(args.format, Some(cli::OutputFormat::Csv)));
+        let args = resolved(&["-o", "events.jsonl"])?;
+        assert!(matches!(args.format, Some(cli::OutputFormat::Ndjson)));
+        let args = resolved(&["-o", "users.unknown"])?;
+        assert!(matches!(args.format, Some(cli::OutputFormat::Csv)));
+        let args = resolved(&["-o", "new users.sql"])?;
+        assert!(matches!(args.format, Some(cli::OutputFormat::Sql)));
+        assert_eq!(args.insert_into.as_deref(), Some("[new users]"));
+        assert!(resolved(&["-f", "sql"]).is_err());
+        let args = resolved(&["--json-envelope"])?;
+        assert!(matches!(args.format, Some(cli::OutputFormat::Json)));
+        assert!(resolved(&["--json-envelope", "-o", "users.csv"]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn envelope() -> Result<()> {
+        let column = |name: &str| mssql::metadata::Column {
+            name: name.to_string(),
+            sql_type: "int".to_string(),
+            nullable: Some(false),
+            precision: None,
+            scale: None,
+        };
+        let mut out = Vec::new();
+        let mut envelope = Envelope::start(&mut out)?;
+        envelope.set(&[column("id"), column("age")])?;
+        envelope.row(&serde_json::json!({"id": 1, "age": 30}))?;
+        envelope.row(&serde_json::json!({"id": 2, "age": null}))?;
+        envelope.end_set()?;
+        envelope.set(&[column("n")])?;
+        envelope.end_set()?;
+        let messages = ["Changed database context to 'app'.".to_string()];
+        envelope.finish(&[2], &messages, Duration::from_millis(15))?;
+
+        let out = String::from_utf8(out)?;
+        assert!(out.ends_with("}\n"));
+        let document: serde_json::Value = serde_json::from_str(&out)?;
+        let columns = |names: &[&str]| {
+            let columns: Vec<_> = names.iter().map(|name| column(name)).collect();
+            serde_json::to_value(columns)
+        };
+        assert_eq!(
+            document,
+            serde_json::json!({
+                "result_sets": [
+                    {
+                        "index": 1,
+                        "columns": columns(&["id", "age"])?,
+                        "rows": [{"id": 1, "age": 30}, {"id": 2, "age": null}],
+                        "row_count": 2,
+                    },
+                    {"index": 2, "columns": columns(&["n"])?, "rows": [], "row_count": 0},
+                ],
+                "rows_affected": [2],
+                "messages": messages,
+                "elapsed_ms": 15,
+            })
+        );
+
+        let mut out = Vec::new();
+        Envelope::start(&mut out)?.finish(&[], &[], Duration::ZERO)?;
+        assert_eq!(
+            String::from_utf8(out)?,
+            "{\"result_sets\":[],\"rows_affected\":[],\"messages\":[],\"elapsed_ms\":0}\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn atomic_file() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("out.csv");
+        let path = path.to_str().context("temp path is not UTF-8")?;
+        std::fs::write(path, "old")?;
+
+        let mut file = AtomicFile::create(path)?;
+        write!(file, "new")?;
+        drop(file);
+        assert_eq!(std::fs::read_to_string(path)?, "old");
+
+        let mut file = AtomicFile::create(path)?;
+        write!(file, "new")?;
+        assert_eq!(std::fs::read_to_string(path)?, "old");
+        file.commit()?;
+        assert_eq!(std::fs::read_to_string(path)?, "new");
+        assert_eq!(std::fs::read_dir(dir.path())?.count(), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,426 @@
+use std::path::Path;
+
+use anyhow::{bail, Context};
+
+use crate::{
+    cli::ParamFormat,
+    params::{Params, TypedName},
+};
+
+type Result<T> = std::result::Result<T, anyhow::Error>;
+
+/// Whether a `-p` argument names a parameter file rather than giving a value.
+pub(crate) fn is_file(arg: &str) -> bool {
+    !arg.contains('=') && Path::new(arg).is_file()
+}
+
+/// Makes a parameter file argument absolute so it can be read from another
+/// working directory, leaving other arguments as they are.
+pub(crate) fn absolute(arg: String) -> Result<String> {
+    if !is_file(&arg) {
+        return Ok(arg);
+    }
+    let path = std::fs::canonicalize(&arg).with_context(|| format!("reading `{arg}`"))?;
+    match path.into_os_string().into_string() {
+        Ok(path) => Ok(path),
+        Err(_) => bail!("path of `{arg}` is not valid UTF-8"),
+    }
+}
+
+/// Reads the rows of a parameter file, in `format` or as told by its extension.
+pub(crate) fn read(path: &str, format: Option<ParamFormat>) -> Result<Vec<Params>> {
+    let format = match format {
+        Some(format) => format,
+        None => detect(path)?,
+    };
+    let text = std::fs::read_to_string(path).with_context(|| format!("reading `{path}`"))?;
+    parse(&text, format).with_context(|| format!("reading parameters from `{path}`"))
+}
+
+fn detect(path: &str) -> Result<ParamFormat> {
+    let ext = Path::new(path)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(str::to_ascii_lowercase);
+    Ok(match ext.as_deref() {
+        Some("toml") => ParamFormat::Toml,
+        Some("json") => ParamFormat::Json,
+        Some("csv") => ParamFormat::Csv,
+        Some("md" | "markdown") => ParamFormat::Markdown,
+        Some("ndjson" | "jsonl") => ParamFormat::Ndjson,
+        _ => bail!("cannot tell the format of parameter file `{path}`, use --param-format"),
+    })
+}
+
+pub(crate) fn parse(text: &str, format: ParamFormat) -> Result<Vec<Params>> {
+    match format {
+        ParamFormat::Toml => toml::parse(text),
+        ParamFormat::Json => json::parse(text),
+        ParamFormat::Csv => csv::parse(text),
+        ParamFormat::Markdown => markdown::parse(text),
+        ParamFormat::Ndjson => json::parse_lines(text),
+    }
+}
+
+/// Builds rows from a header of `name[:type]` columns and rows of text cells.
+/// An empty cell in a typed column is a `NULL`, in an untyped one an empty string.
+fn text_rows<R, C>(header: &[String], rows: R) -> Result<Vec<Params>>
+where
+    R: IntoIterator<Item = Result<Vec<C>>>,
+    C: AsRef<str>,
+{
+    let names = header
+        .iter()
+        .map(|spec| TypedName::parse(spec.trim()))
+        .collect::<Result<Vec<_>>>()?;
+    rows.into_iter()
+        .enumerate()
+        .map(|(idx, cells)| {
+            let cells = cells?;
+            if cells.len() != names.len() {
+                bail!(
+                    "row {} has {} cells, expected {}",
+                    idx + 1,
+                    cells.len(),
+                    names.len()
+                );
+            }
+            let mut params = Params::default();
+            for (name, cell) in names.iter().zip(&cells) {
+                let cell = cell.as_ref();
+                let text = (!cell.is_empty() || !name.is_typed()).then_some(cell);
+                params.insert(name.name, name.value(text)?);
+            }
+            Ok(params)
+        })
+        .collect()
+}
+
+mod toml {
+    use ::toml::{Table, Value as Toml};
+    use anyhow::bail;
+
+    use super::Result;
+    use crate::params::{Params, Type, TypedName, Value};
+
+    /// A table of values is one row, a table holding a single array of
+    /// tables (`[[rows]]`) is a row per table.
+    pub(super) fn parse(text: &str) -> Result<Vec<Params>> {
+        let table = text.parse::<Table>()?;
+        let mut values = table.values();
+        if let (Some(Toml::Array(rows)), None) = (values.next(), values.next()) {
+            if rows.iter().all(Toml::is_table) {
+                return rows.iter().filter_map(Toml::as_table).map(row).collect();
+            }
+        }
+        Ok(vec![row(&table)?])
+    }
+
+    fn row(table: &Table) -> Result<Params> {
+        let mut params = Params::default();
+        for (spec, value) in table {
+            let name = TypedName::parse(spec)?;
+            params.insert(name.name, self::value(&name, value)?);
+        }
+        Ok(params)
+    }
+
+    fn value(name: &TypedName, value: &Toml) -> Result<Value> {
+        if name.is_typed() {
+            return match value {
+                Toml::String(s) => name.value(Some(s)),
+                Toml::Integer(_) | Toml::Float(_) | Toml::Boolean(_) | Toml::Datetime(_) => {
+                    name.value(Some(&value.to_string()))
+                }
+                _ => bail!("parameter `{}` is not a single value", name.name),
+            };
+        }
+        Ok(match value {
+            Toml::String(s) => Value::String(s.clone()),
+            Toml::Integer(i) => Value::I64(*i),
+            Toml::Float(f) => Value::F64(*f),
+            Toml::Boolean(b) => Value::Bit(*b),
+            Toml::Datetime(dt) => {
+                let ty = match (dt.date, dt.time, dt.offset) {
+                    (Some(_), Some(_), Some(_)) => Type::DateTimeOffset,
+                    (Some(_), Some(_), None) => Type::DateTime2,
+                    (Some(_), None, _) => Type::Date,
+                    (None, _, _) => Type::Time,
+                };
+                ty.parse_value(&dt.to_string())?
+            }
+            _ => bail!("parameter `{}` is not a single value", name.name),
+        })
+    }
+}
+
+mod json {
+    use anyhow::{anyhow, bail};
+    use serde_json::{Map, Value as Json};
+
+    use super::Result;
+    use crate::params::{Params, TypedName, Value};
+
+    /// An object is one row, an array of objects is a row per object.
+    pub(super) fn parse(text: &str) -> Result<Vec<Params>> {
+        match serde_json::from_str(text)? {
+            Json::Object(object) => Ok(vec![row(&object)?]),
+            Json::Array(rows) => rows
+                .iter()
+                .map(|value| match value {
+                    Json::Object(object) => row(object),
+                    _ => bail!("expected an object of parameters"),
+                })
+                .collect(),
+            _ => bail!("expected an object or array of objects"),
+        }
+    }
+
+    /// An object per line, blank lines are skipped.
+    pub(super) fn parse_lines(text: &str) -> Result<Vec<Params>> {
+        text.lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(idx, line)| match serde_json::from_str(line) {
+                Ok(Json::Object(object)) => row(&object),
+                Ok(_) => bail!("line {}: expected an object of parameters", idx + 1),
+                Err(e) => bail!("line {}: {e}", idx + 1),
+            })
+            .collect()
+    }
+
+    fn row(object: &Map<String, Json>) -> Result<Params> {
+        let mut params = Params::default();
+        for (spec, value) in object {
+            let name = TypedName::parse(spec)?;
+            params.insert(name.name, self::value(&name, value)?);
+        }
+        Ok(params)
+    }
+
+    fn value(name: &TypedName, value: &Json) -> Result<Value> {
+        if name.is_typed() {
+            return match value {
+                Json::Null => name.value(None),
+                Json::String(s) => name.value(Some(s)),
+                Json::Number(_) | Json::Bool(_) => name.value(Some(&value.to_string())),
+                _ => bail!("parameter `{}` is not a single value", name.name),
+            };
+        }
+        Ok(match value {
+            Json::Null => name.value(None)?,
+            Json::String(s) => Value::String(s.clone()),
+            Json::Number(n) => match n.as_i64() {
+                Some(i) => Value::I64(i),
+                None => Value::F64(
+                    n.as_f64()
+                        .ok_or_else(|| anyhow!("number {n} is out of range"))?,
+                ),
+            },
+            Json::Bool(b) => Value::Bit(*b),
+            _ => bail!("parameter `{}` is not a single value", name.name),
+        })
+    }
+}
+
+mod csv {
+    use super::Result;
+    use crate::params::Params;
+
+    /// The header names the parameters, each record is a row.
+    pub(super) fn parse(text: &str) -> Result<Vec<Params>> {
+        let mut reader = ::csv::ReaderBuilder::new()
+            .flexible(true)
+            .from_reader(text.as_bytes());
+        let header = reader
+            .headers()?
+            .iter()
+            .map(String::from)
+            .collect::<Vec<_>>();
+        let rows = reader
+            .records()
+            .map(|record| Ok(record?.iter().map(String::from).collect()));
+        super::text_rows::<_, String>(&header, rows)
+    }
+}
+
+mod markdown {
+    use anyhow::bail;
+
+    use super::Result;
+    use crate::params::Params;
+
+    /// Reads the first table in the document, the header row naming the
+    /// parameters. Text around the table is ignored.
+    pub(super) fn parse(text: &str) -> Result<Vec<Params>> {
+        let mut lines = text
+            .lines()
+            .map(str::trim)
+            .skip_while(|l| !l.starts_with('|'));
+        let Some(header) = lines.next() else {
+            bail!("no table found");
+        };
+        match lines.next() {
+            Some(line) if is_delimiter_row(line) => {}
+            _ => bail!("table header is not followed by a `|---|` row"),
+        }
+        let header = cells(header);
+        let rows = lines
+            .take_while(|l| l.starts_with('|'))
+            .map(|l| Ok(cells(l)));
+        super::text_rows::<_, String>(&header, rows)
+    }
+
+    fn is_delimiter_row(line: &str) -> bool {
+        cells(line).iter().all(|cell| {
+            let dashes = cell.trim_start_matches(':').trim_end_matches(':');
+            !dashes.is_empty() && dashes.bytes().all(|b| b == b'-')
+        })
+    }
+
+    /// Splits `| a | b \| c |` into trimmed cells, `\|` being a literal pipe.
+    fn cells(line: &str) -> Vec<String> {
+        let line = line.strip_prefix('|').unwrap_or(line);
+        let mut cells = Vec::new();
+        let mut cell = String::new();
+        let mut chars = line.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some('|') => cell.push('|'),
+                    Some(c) => {
+                        cell.push('\\');
+                        cell.push(c);
+                    }
+                    None => cell.push('\\'),
+                },
+                '|' => cells.push(std::mem::take(&mut cell).trim().to_string()),
+                c => cell.push(c),
+            }
+        }
+        // a row may leave out the closing pipe
+        if !cell.trim().is_empty() {
+            cells.push(cell.trim().to_string());
+        }
+        cells
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+    use chrono::NaiveDate;
+
+    use super::*;
+    use crate::{
+        params::{Type, Value},
+        sql::Template,
+    };
+
+    fn bound(rows: &[Params], sql: &str) -> Result<Vec<Vec<Value>>> {
+        let template = Template::parse(sql);
+        rows.iter().map(|row| row.values_for(&template)).collect()
+    }
+
+    #[test]
+    fn toml() -> Result<()> {
+        let rows = parse(
+            "id = 1\nname = 'a'\n'at:date' = '2023-01-02'\nwhen = 2023-01-02",
+            ParamFormat::Toml,
+        )?;
+        let date = NaiveDate::from_ymd_opt(2023, 1, 2).ok_or_else(|| anyhow!("invalid date"))?;
+        assert_eq!(
+            bound(&rows, "SELECT $id, $name, $at, $when")?,
+            [vec![
+                Value::I64(1),
+                Value::String("a".into()),
+                Value::Date(date),
+                Value::Date(date),
+            ]]
+        );
+
+        let rows = parse(
+            "[[rows]]\n'id:int' = 1\n[[rows]]\n'id:int' = 2",
+            ParamFormat::Toml,
+        )?;
+        assert_eq!(
+            bound(&rows, "SELECT $id")?,
+            [vec![Value::I32(1)], vec![Value::I32(2)]]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn json() -> Result<()> {
+        let rows = parse(
+            r#"[{"id": 1, "ok": true, "n": null}, {"id": 2.5, "ok": false, "n:int": null}]"#,
+            ParamFormat::Json,
+        )?;
+        assert_eq!(
+            bound(&rows, "SELECT $id, $ok, $n")?,
+            [
+                vec![Value::I64(1), Value::Bit(true), Value::Null(Type::NVarChar)],
+                vec![Value::F64(2.5), Value::Bit(false), Value::Null(Type::Int)],
+            ]
+        );
+        assert!(parse(r#"{"id": [1]}"#, ParamFormat::Json).is_err());
+
+        let rows = parse("{\"id\": 1}\n\n{\"id\": 2}\n", ParamFormat::Ndjson)?;
+        assert_eq!(
+            bound(&rows, "SELECT $id")?,
+            [vec![Value::I64(1)], vec![Value::I64(2)]]
+        );
+        assert!(parse("{\"id\": 1}\n[1]\n", ParamFormat::Ndjson).is_err());
+        assert!(parse("1", ParamFormat::Json).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn csv() -> Result<()> {
+        let rows = parse("id:int,name\n1,\"a, b\"\n,\n", ParamFormat::Csv)?;
+        assert_eq!(
+            bound(&rows, "SELECT $id, $name")?,
+            [
+                vec![Value::I32(1), Value::String("a, b".into())],
+                vec![Value::Null(Type::Int), Value::String("".into())],
+            ]
+        );
+        assert!(parse("id:int\nx\n", ParamFormat::Csv).is_err());
+        assert!(parse("a,b\n1\n", ParamFormat::Csv).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn markdown() -> Result<()> {
+        let text = "\
+# Test users
+
+| id:int | name      |
+|-------:|:----------|
+| 1      | a \\| b   |
+| 2      |           |
+
+Anything after the table is ignored.
+";
+        let rows = parse(text, ParamFormat::Markdown)?;
+        assert_eq!(
+            bound(&rows, "SELECT $id, $name")?,
+            [
+                vec![Value::I32(1), Value::String("a | b".into())],
+                vec![Value::I32(2), Value::String("".into())],
+            ]
+        );
+        assert!(parse("no table here", ParamFormat::Markdown).is_err());
+        assert!(parse("| a |\n| 1 |", ParamFormat::Markdown).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn detect_format() -> Result<()> {
+        assert!(matches!(detect("users.CSV")?, ParamFormat::Csv));
+        assert!(matches!(detect("runbook.md")?, ParamFormat::Markdown));
+        assert!(matches!(detect("rows.jsonl")?, ParamFormat::Ndjson));
+        assert!(detect("users.txt").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,555 @@
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, bail, Context};
+use chrono::{DateTime, FixedOffset, NaiveDate, NaiveDateTime, NaiveTime, Timelike};
+use tiberius::{numeric::Numeric, ColumnData, Uuid};
+
+use crate::{cli, fmt_util, param_source, sql};
+
+type Result<T> = std::result::Result<T, anyhow::Error>;
+
+/// SQL type a parameter is sent as, given with `-p name:type=value`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum Type {
+    #[default]
+    NVarChar,
+    TinyInt,
+    SmallInt,
+    Int,
+    BigInt,
+    Real,
+    Float,
+    /// `decimal` or `numeric`, optionally with the scale to send the value at.
+    Decimal(Option<u8>),
+    Bit,
+    UniqueIdentifier,
+    VarBinary,
+    Date,
+    Time,
+    DateTime2,
+    DateTimeOffset,
+}
+
+impl Type {
+    pub(crate) fn parse(s: &str) -> Result<Self> {
+        let lower = s.trim().to_ascii_lowercase();
+        let (name, args) = match lower.split_once('(') {
+            Some((name, args)) => (name.trim(), Some(args.trim_end_matches(')'))),
+            None => (lower.as_str(), None),
+        };
+        Ok(match (name, args) {
+            ("decimal" | "numeric", Some(args)) => {
+                // (precision, scale) or just (precision), which means a scale of 0
+                let (precision, scale) = args.split_once(',').unwrap_or((args, "0"));
+                let parse = |n: &str| n.trim().parse::<u8>();
+                let (precision, scale) = (parse(precision), parse(scale));
+                let (Ok(precision), Ok(scale)) = (precision, scale) else {
+                    bail!("invalid type `{s}`");
+                };
+                if !(1..=38).contains(&precision) {
+                    bail!("invalid type `{s}`, precision must be 1 to 38");
+                }
+                if scale > precision || scale >= 38 {
+                    bail!("invalid type `{s}`, scale must be below 38 and at most the precision");
+                }
+                Type::Decimal(Some(scale))
+            }
+            ("decimal" | "numeric", None) => Type::Decimal(None),
+            // tiberius sends all text as nvarchar, which a varchar column would
+            // be converted to for the comparison, and dates as datetime2
+            ("varchar" | "char", _) => bail!(
+                "parameters cannot be sent as `{s}`, text is sent as `nvarchar`: \
+                 give `nvarchar` and CAST it in the script"
+            ),
+            ("datetime" | "smalldatetime", None) => bail!(
+                "parameters cannot be sent as `{s}`, dates and times are sent as `datetime2`: \
+                 give `datetime2` and CAST it in the script"
+            ),
+            (_, Some(_)) if name.ends_with("char") || name.ends_with("binary") => {
+                // lengths only matter to the server, values are sent at their own length
+                Type::parse(name)?
+            }
+            ("nvarchar" | "nchar" | "string", None) => Type::NVarChar,
+            ("tinyint", None) => Type::TinyInt,
+            ("smallint", None) => Type::SmallInt,
+            ("int", None) => Type::Int,
+            ("bigint", None) => Type::BigInt,
+            ("real", None) => Type::Real,
+            ("float", None) => Type::Float,
+            ("bit" | "bool", None) => Type::Bit,
+            ("uniqueidentifier" | "guid" | "uuid", None) => Type::UniqueIdentifier,
+            ("varbinary" | "binary", None) => Type::VarBinary,
+            ("date", None) => Type::Date,
+            ("time", None) => Type::Time,
+            ("datetime2", None) => Type::DateTime2,
+            ("datetimeoffset", None) => Type::DateTimeOffset,
+            _ => bail!("unknown parameter type `{s}`"),
+        })
+    }
+
+    pub(crate) fn parse_value(self, s: &str) -> Result<Value> {
+        let t = s.trim();
+        Ok(match self {
+            Type::NVarChar => Value::String(s.to_string()),
+            Type::TinyInt => Value::U8(t.parse()?),
+            Type::SmallInt => Value::I16(t.parse()?),
+            Type::Int => Value::I32(t.parse()?),
+            Type::BigInt => Value::I64(t.parse()?),
+            Type::Real => Value::F32(t.parse()?),
+            Type::Float => Value::F64(t.parse()?),
+            Type::Decimal(scale) => Value::Numeric(parse_decimal(t, scale)?),
+            Type::Bit => match t.to_ascii_lowercase().as_str() {
+                "1" | "true" => Value::Bit(true),
+                "0" | "false" => Value::Bit(false),
+                _ => bail!("expected 1, 0, true or false"),
+            },
+            Type::UniqueIdentifier => Value::Guid(Uuid::parse_str(t)?),
+            Type::VarBinary => Value::Binary(parse_hex(t)?),
+            Type::Date => Value::Date(NaiveDate::parse_from_str(t, "%Y-%m-%d")?),
+            Type::Time => Value::Time(NaiveTime::parse_from_str(t, "%H:%M:%S%.f")?),
+            Type::DateTime2 => Value::DateTime2(
+                NaiveDateTime::parse_from_str(t, "%Y-%m-%dT%H:%M:%S%.f")
+                    .or_else(|_| NaiveDateTime::parse_from_str(t, "%Y-%m-%d %H:%M:%S%.f"))?,
+            ),
+            Type::DateTimeOffset => Value::DateTimeOffset(
+                DateTime::parse_from_rfc3339(t)
+                    .or_else(|_| DateTime::parse_from_str(t, "%Y-%m-%d %H:%M:%S%.f %:z"))?,
+            ),
+        })
+    }
+}
+
+/// Parses `-12.345` into a [`Numeric`], at `scale` decimal places if given.
+fn parse_decimal(s: &str, scale: Option<u8>) -> Result<Numeric> {
+    let (negative, digits) = match s.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, s.strip_prefix('+').unwrap_or(s)),
+    };
+    let (int, frac) = digits.split_once('.').unwrap_or((digits, ""));
+    if int.is_empty() && frac.is_empty()
+        || !int.bytes().chain(frac.bytes()).all(|b| b.is_ascii_digit())
+    {
+        bail!("invalid decimal");
+    }
+    let scale = match scale {
+        Some(scale) if frac.len() > scale as usize => bail!("more than {scale} decimal places"),
+        Some(scale) => scale as usize,
+        None if frac.len() >= 38 => bail!("more than 37 decimal places"),
+        None => frac.len(),
+    };
+    let digits = format!("{int}{frac:0<scale$}");
+    let digits = digits.trim_start_matches('0');
+    if digits.len() > 38 {
+        bail!("more than 38 digits");
+    }
+    let value = if digits.is_empty() {
+        0
+    } else {
+        digits.parse::<i128>()?
+    };
+    let value = if negative { -value } else { value };
+    Ok(Numeric::new_with_scale(value, scale as u8))
+}
+
+/// Parses hex digits, with or without a leading `0x`, into bytes.
+fn parse_hex(s: &str) -> Result<Vec<u8>> {
+    let hex = s
+        .strip_prefix("0x")
+        .or_else(|| s.strip_prefix("0X"))
+        .unwrap_or(s);
+    if !hex.len().is_multiple_of(2) {
+        bail!("odd number of hex digits");
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            let byte = hex
+                .get(i..i + 2)
+                .ok_or_else(|| anyhow!("invalid hex digit"))?;
+            u8::from_str_radix(byte, 16).map_err(|_| anyhow!("invalid hex digits `{byte}`"))
+        })
+        .collect()
+}
+
+/// A value bound to a `$name` placeholder.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Value {
+    /// A `NULL`, sent as the given type.
+    Null(Type),
+    String(String),
+    U8(u8),
+    I16(i16),
+    I32(i32),
+    I64(i64),
+    F32(f32),
+    F64(f64),
+    Numeric(Numeric),
+    Bit(bool),
+    Guid(Uuid),
+    Binary(Vec<u8>),
+    Date(NaiveDate),
+    Time(NaiveTime),
+    DateTime2(NaiveDateTime),
+    DateTimeOffset(DateTime<FixedOffset>),
+}
+
+impl Value {
+    /// The value written as a T-SQL literal of the type it would be bound as.
+    pub(crate) fn to_literal(&self) -> Result<String> {
+        let literal = match self {
+            Value::Null(_) => "NULL".to_string(),
+            Value::String(s) => format!("N'{}'", s.replace('\'', "''")),
+            Value::U8(i) => i.to_string(),
+            Value::I16(i) => i.to_string(),
+            Value::I32(i) => i.to_string(),
+            Value::I64(i) => i.to_string(),
+            // the exponent makes these float literals rather than decimals
+            Value::F32(f) if f.is_finite() => format!("CAST({f:e} AS real)"),
+            Value::F64(f) if f.is_finite() => format!("{f:e}"),
+            Value::F32(f) => bail!("{f} cannot be written as a SQL literal"),
+            Value::F64(f) => bail!("{f} cannot be written as a SQL literal"),
+            Value::Numeric(n) => fmt_util::decimal(*n),
+            Value::Bit(b) => u8::from(*b).to_string(),
+            Value::Guid(g) => format!("'{}'", g.to_string().to_uppercase()),
+            Value::Binary(b) => {
+                let hex: String = b.iter().map(|byte| format!("{byte:02X}")).collect();
+                format!("0x{hex}")
+            }
+            // yyyymmdd is read the same whatever the session's DATEFORMAT
+            Value::Date(d) => d.format("'%Y%m%d'").to_string(),
+            Value::Time(t) => format!("CAST('{}' AS time)", time_literal(t)),
+            Value::DateTime2(dt) => format!(
+                "CAST('{}T{}' AS datetime2)",
+                dt.format("%Y-%m-%d"),
+                time_literal(&dt.time())
+            ),
+            Value::DateTimeOffset(dt) => format!(
+                "CAST('{}T{}{}' AS datetimeoffset)",
+                dt.format("%Y-%m-%d"),
+                time_literal(&dt.time()),
+                dt.format("%:z")
+            ),
+        };
+        // parenthesized so that `-$x` does not turn into a `--` comment
+        Ok(match literal.starts_with('-') {
+            true => format!("({literal})"),
+            false => literal,
+        })
+    }
+}
+
+/// `hh:mm:ss` with the 7 fractional digits SQL Server keeps, if any.
+fn time_literal(t: &NaiveTime) -> String {
+    match t.nanosecond() / 100 {
+        0 => t.format("%H:%M:%S").to_string(),
+        ticks => format!("{}.{ticks:07}", t.format("%H:%M:%S")),
+    }
+}
+
+impl<'a> tiberius::IntoSql<'a> for Value {
+    fn into_sql(self) -> ColumnData<'a> {
+        match self {
+            Value::Null(ty) => match ty {
+                Type::NVarChar => ColumnData::String(None),
+                Type::TinyInt => ColumnData::U8(None),
+                Type::SmallInt => ColumnData::I16(None),
+                Type::Int => ColumnData::I32(None),
+                Type::BigInt => ColumnData::I64(None),
+                Type::Real => ColumnData::F32(None),
+                Type::Float => ColumnData::F64(None),
+                Type::Decimal(_) => ColumnData::Numeric(None),
+                Type::Bit => ColumnData::Bit(None),
+                Type::UniqueIdentifier => ColumnData::Guid(None),
+                Type::VarBinary => ColumnData::Binary(None),
+                Type::Date => ColumnData::Date(None),
+                Type::Time => ColumnData::Time(None),
+                Type::DateTime2 => ColumnData::DateTime2(None),
+                Type::DateTimeOffset => ColumnData::DateTimeOffset(None),
+            },
+            Value::String(s) => s.into_sql(),
+            Value::U8(i) => i.into_sql(),
+            Value::I16(i) => i.into_sql(),
+            Value::I32(i) => i.into_sql(),
+            Value::I64(i) => i.into_sql(),
+            Value::F32(f) => f.into_sql(),
+            Value::F64(f) => f.into_sql(),
+            Value::Numeric(n) => n.into_sql(),
+            Value::Bit(b) => b.into_sql(),
+            Value::Guid(g) => g.into_sql(),
+            Value::Binary(b) => b.into_sql(),
+            Value::Date(d) => d.into_sql(),
+            Value::Time(t) => t.into_sql(),
+            Value::DateTime2(dt) => dt.into_sql(),
+            Value::DateTimeOffset(dt) => dt.into_sql(),
+        }
+    }
+}
+
+/// Named parameter values, as given by `-p name[:type]=value` or read from
+/// parameter files.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct Params(BTreeMap<String, Value>);
+
+impl Params {
+    /// Collects `-p` arguments in order, a later value for a name replacing an
+    /// earlier one. Files are read with `format`, or by their extension, and
+    /// must hold a single row.
+    pub(crate) fn from_args(args: &[String], format: Option<cli::ParamFormat>) -> Result<Self> {
+        let mut rows = Self::rows_from_args(args, format)?;
+        match rows.len() {
+            1 => Ok(rows.pop().unwrap_or_default()),
+            n => bail!("parameter files hold {n} rows but only one can be used here"),
+        }
+    }
+
+    /// Like [`Params::from_args`], but a file may hold many rows. Values from
+    /// the other arguments are shared by all of its rows.
+    pub(crate) fn rows_from_args(
+        args: &[String],
+        format: Option<cli::ParamFormat>,
+    ) -> Result<Vec<Self>> {
+        let mut rows = vec![Self::default()];
+        let mut many_rows: Option<&str> = None;
+        for arg in args {
+            if !param_source::is_file(arg) {
+                let (name, value) = parse_arg(arg)?;
+                rows.iter_mut()
+                    .for_each(|row| row.insert(name, value.clone()));
+                continue;
+            }
+            let file_rows = param_source::read(arg, format)?;
+            if let [file_row] = file_rows.as_slice() {
+                rows.iter_mut().for_each(|row| row.extend(file_row.clone()));
+                continue;
+            }
+            if let Some(other) = many_rows.replace(arg) {
+                bail!("only one parameter file can hold more than one row, `{other}` and `{arg}` both do");
+            }
+            // with no file of many rows before this one there is exactly one row
+            let shared = rows.pop().unwrap_or_default();
+            rows = file_rows
+                .into_iter()
+                .map(|file_row| {
+                    let mut row = shared.clone();
+                    row.extend(file_row);
+                    row
+                })
+                .collect();
+        }
+        Ok(rows)
+    }
+
+    pub(crate) fn insert(&mut self, name: &str, value: Value) {
+        self.0.insert(name.to_string(), value);
+    }
+
+    pub(crate) fn extend(&mut self, other: Params) {
+        self.0.extend(other.0);
+    }
+
+    /// Values for the template's placeholders, in bind order.
+    pub(crate) fn values_for(&self, template: &sql::Template) -> Result<Vec<Value>> {
+        template
+            .names()
+            .iter()
+            .map(|name| match self.0.get(*name) {
+                Some(value) => Ok(value.clone()),
+                None => bail!("no value given for parameter `${name}`"),
+            })
+            .collect()
+    }
+}
+
+/// A parameter name with the type its values are sent as, `name` or
+/// `name:type`. Untyped values are sent as strings.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct TypedName<'a> {
+    pub name: &'a str,
+    ty: Option<(&'a str, Type)>,
+}
+
+impl<'a> TypedName<'a> {
+    pub(crate) fn parse(spec: &'a str) -> Result<Self> {
+        let (name, ty) = match spec.split_once(':') {
+            Some((name, ty_name)) => (name, Some((ty_name, Type::parse(ty_name)?))),
+            None => (spec, None),
+        };
+        Ok(Self { name, ty })
+    }
+
+    pub(crate) fn is_typed(&self) -> bool {
+        self.ty.is_some()
+    }
+
+    /// Parses `text` as this parameter's type, `None` being a `NULL`.
+    pub(crate) fn value(&self, text: Option<&str>) -> Result<Value> {
+        Ok(match (self.ty, text) {
+            (Some((ty_name, ty)), Some(text)) => ty.parse_value(text).with_context(|| {
+                format!(
+                    "invalid {ty_name} value `{text}` for parameter `{}`",
+                    self.name
+                )
+            })?,
+            (None, Some(text)) => Value::String(text.to_string()),
+            (Some((_, ty)), None) => Value::Null(ty),
+            (None, None) => Value::Null(Type::NVarChar),
+        })
+    }
+}
+
+/// Parses `name=value`, `name:type=value` or `name:type`, the last being a typed `NULL`.
+fn parse_arg(arg: &str) -> Result<(&str, Value)> {
+    let (spec, value) = match arg.split_once('=') {
+        Some((spec, value)) => (spec, Some(value)),
+        None => (arg, None),
+    };
+    let name = TypedName::parse(spec)?;
+    if value.is_none() && !name.is_typed() {
+        bail!("parameter `{arg}` is not in the form name[:type]=value, nor a file");
+    }
+    Ok((name.name, name.value(value)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_in_bind_order() -> Result<()> {
+        let params = Params::from_args(&["b=2".into(), "a=x=1".into(), "c=".into()], None)?;
+        let template = sql::Template::parse("SELECT $a, $b, $a");
+        assert_eq!(
+            params.values_for(&template)?,
+            [Value::String("x=1".into()), Value::String("2".into())]
+        );
+
+        let template = sql::Template::parse("SELECT $d");
+        assert!(params.values_for(&template).is_err());
+        assert!(Params::from_args(&["a".into()], None).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn rows_share_values() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let csv = dir.path().join("rows.csv");
+        std::fs::write(&csv, "id:int,name\n1,a\n2,b\n")?;
+        let csv = csv.to_string_lossy().to_string();
+        let args = ["name=x".into(), csv.clone(), "tag=t".into()];
+
+        let rows = Params::rows_from_args(&args, None)?;
+        let template = sql::Template::parse("SELECT $id, $name, $tag");
+        let values = rows
+            .iter()
+            .map(|row| row.values_for(&template))
+            .collect::<Result<Vec<_>>>()?;
+        let s = |s: &str| Value::String(s.into());
+        assert_eq!(
+            values,
+            [
+                vec![Value::I32(1), s("a"), s("t")],
+                vec![Value::I32(2), s("b"), s("t")],
+            ]
+        );
+        assert!(Params::from_args(&args, None).is_err());
+        assert!(Params::rows_from_args(&[csv.clone(), csv], None).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn literals() -> Result<()> {
+        let literal = |arg| parse_arg(arg).and_then(|(_, value)| value.to_literal());
+        assert_eq!(literal("s=it's")?, "N'it''s'");
+        assert_eq!(literal("n:int")?, "NULL");
+        assert_eq!(literal("n:int=-5")?, "(-5)");
+        assert_eq!(literal("f:float=1.5")?, "1.5e0");
+        assert!(literal("f:float=inf").is_err());
+        assert_eq!(literal("d:decimal=-0.05")?, "(-0.05)");
+        assert_eq!(literal("f:float=-2")?, "(-2e0)");
+        let negated = sql::Template::parse("SELECT -$n").fill_rows(1, &[literal("n:int=-5")?]);
+        assert_eq!(negated, "SELECT -(-5)");
+        assert_eq!(literal("d:decimal(5,0)=12")?, "12");
+        assert_eq!(literal("b:bit=true")?, "1");
+        assert_eq!(literal("b:varbinary=0a00ff")?, "0x0A00FF");
+        assert_eq!(literal("b:varbinary=")?, "0x");
+        assert_eq!(literal("d:date=2023-01-02")?, "'20230102'");
+        assert_eq!(
+            literal("t:datetime2=2023-01-02 03:04:05.12")?,
+            "CAST('2023-01-02T03:04:05.1200000' AS datetime2)"
+        );
+        assert_eq!(
+            literal("t:datetimeoffset=2023-01-02T03:04:05-05:30")?,
+            "CAST('2023-01-02T03:04:05-05:30' AS datetimeoffset)"
+        );
+        assert_eq!(literal("t:time=03:04:05")?, "CAST('03:04:05' AS time)");
+        assert_eq!(
+            literal("g:uniqueidentifier=6f9619ff-8b86-d011-b42d-00c04fc964ff")?,
+            "'6F9619FF-8B86-D011-B42D-00C04FC964FF'"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn typed_values() -> Result<()> {
+        let parse = |arg| parse_arg(arg).map(|(_, value)| value);
+        assert_eq!(parse("id:int=5")?, Value::I32(5));
+        assert_eq!(parse("id:TINYINT=255")?, Value::U8(255));
+        assert!(parse("id:tinyint=256").is_err());
+        assert_eq!(parse("id:bigint")?, Value::Null(Type::BigInt));
+        assert_eq!(parse("f:bit=true")?, Value::Bit(true));
+        assert_eq!(parse("s:nvarchar(10)=abc")?, Value::String("abc".into()));
+        assert_eq!(
+            parse("b:varbinary=0x0A0b")?,
+            Value::Binary(vec![0x0a, 0x0b])
+        );
+        assert!(parse("b:varbinary=0xA").is_err());
+        let error = |arg| {
+            parse(arg)
+                .err()
+                .map(|e| format!("{e:#}"))
+                .unwrap_or_default()
+        };
+        assert!(error("s:varchar(10)=abc")
+            .contains("cannot be sent as `varchar(10)`, text is sent as `nvarchar`"));
+        assert!(error("s:char=a").contains("`nvarchar`"));
+        assert!(error("when:datetime=2023-01-02").contains("sent as `datetime2`"));
+        assert!(error("when:smalldatetime=2023-01-02").contains("sent as `datetime2`"));
+        assert_eq!(
+            parse("d:decimal=-12.50")?,
+            Value::Numeric(Numeric::new_with_scale(-1250, 2))
+        );
+        assert_eq!(
+            parse("d:decimal(10,3)=1.5")?,
+            Value::Numeric(Numeric::new_with_scale(1500, 3))
+        );
+        assert!(parse("d:decimal(10,1)=1.55").is_err());
+        assert!(parse("d:decimal=1.2.3").is_err());
+        for t in [
+            "decimal(0,0)",
+            "decimal(39)",
+            "decimal(5,6)",
+            "decimal(38,38)",
+            "decimal(x)",
+        ] {
+            assert!(parse_arg(&format!("d:{t}=1")).is_err(), "{t}");
+        }
+        assert!(parse_arg(&format!("d:decimal=0.{}", "1".repeat(38))).is_err());
+        let when = NaiveDate::from_ymd_opt(2023, 1, 2)
+            .and_then(|d| d.and_hms_nano_opt(3, 4, 5, 123_456_700))
+            .ok_or_else(|| anyhow!("invalid date"))?;
+        assert_eq!(
+            parse("when:datetime2=2023-01-02 03:04:05.1234567")?,
+            Value::DateTime2(when)
+        );
+        assert!(matches!(
+            parse("when:datetimeoffset=2023-01-02T03:04:05+02:00")?,
+            Value::DateTimeOffset(_)
+        ));
+        assert!(matches!(
+            parse("g:uniqueidentifier=6F9619FF-8B86-D011-B42D-00C04FC964FF")?,
+            Value::Guid(_)
+        ));
+        assert!(parse("x:nope=1").is_err());
+        Ok(())
+    }
+}
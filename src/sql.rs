@@ -0,0 +1,573 @@
+use std::ops::Range;
+
+use anyhow::{bail, Context};
+
+type Result<T> = std::result::Result<T, anyhow::Error>;
+
+/// Kind of a piece of T-SQL as split up by [`Tokens`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Token {
+    /// Anything not covered by the other kinds.
+    Code,
+    /// A string literal, `'...'` or `N'...'`.
+    Literal,
+    /// A delimited identifier, `[...]` or `"..."`.
+    Identifier,
+    /// A `-- ...` or `/* ... */` comment.
+    Comment,
+    /// A `$name` parameter placeholder.
+    Placeholder,
+}
+
+/// `$` names T-SQL gives a meaning of its own, such as `$action` in a `MERGE`
+/// output or `$IDENTITY`, which are code rather than placeholders.
+const PSEUDO_COLUMNS: [&str; 8] = [
+    "action",
+    "identity",
+    "rowguid",
+    "partition",
+    "node_id",
+    "edge_id",
+    "from_id",
+    "to_id",
+];
+
+/// Splits T-SQL into pieces so that text inside literals, identifiers and
+/// comments is never mistaken for anything else. An unterminated literal,
+/// identifier or comment runs to the end of the input.
+pub(crate) struct Tokens<'a> {
+    sql: &'a str,
+    pos: usize,
+}
+
+impl<'a> Tokens<'a> {
+    pub(crate) fn new(sql: &'a str) -> Self {
+        Self { sql, pos: 0 }
+    }
+
+    fn token_len(&self, bytes: &[u8], at: usize) -> Option<(Token, usize)> {
+        let rest = &bytes[at..];
+        match rest {
+            [b'\'', ..] => Some((Token::Literal, quoted_len(rest, b'\''))),
+            [b'"', ..] => Some((Token::Identifier, quoted_len(rest, b'"'))),
+            [b'[', ..] => Some((Token::Identifier, quoted_len(rest, b']'))),
+            [b'-', b'-', ..] => {
+                let len = rest.iter().position(|b| *b == b'\n').unwrap_or(rest.len());
+                Some((Token::Comment, len))
+            }
+            [b'/', b'*', ..] => Some((Token::Comment, block_comment_len(rest))),
+            [b'$', c, ..] if is_ident_start(*c) && (at == 0 || !is_ident(bytes[at - 1])) => {
+                let len = 1 + rest[1..].iter().take_while(|b| is_ident(**b)).count();
+                let name = &rest[1..len];
+                let pseudo = PSEUDO_COLUMNS
+                    .iter()
+                    .any(|p| p.as_bytes().eq_ignore_ascii_case(name));
+                (!pseudo).then_some((Token::Placeholder, len))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl<'a> Iterator for Tokens<'a> {
+    type Item = (Token, &'a str);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let bytes = self.sql.as_bytes();
+        let start = self.pos;
+        if start >= bytes.len() {
+            return None;
+        }
+        let (token, len) = match self.token_len(bytes, start) {
+            Some(token) => token,
+            None => {
+                let len = (start + 1..bytes.len())
+                    .find(|i| self.token_len(bytes, *i).is_some())
+                    .unwrap_or(bytes.len())
+                    - start;
+                (Token::Code, len)
+            }
+        };
+        // all token boundaries are ASCII so this never splits a character
+        self.pos = start + len;
+        Some((token, &self.sql[start..self.pos]))
+    }
+}
+
+/// Length of a quoted run starting at `s[0]`, a doubled `close` is an escape.
+fn quoted_len(s: &[u8], close: u8) -> usize {
+    let mut i = 1;
+    while i < s.len() {
+        if s[i] == close {
+            if s.get(i + 1) == Some(&close) {
+                i += 2;
+                continue;
+            }
+            return i + 1;
+        }
+        i += 1;
+    }
+    s.len()
+}
+
+/// Length of a `/* */` comment starting at `s[0]`, T-SQL allows nesting them.
+fn block_comment_len(s: &[u8]) -> usize {
+    let mut depth = 0;
+    let mut i = 0;
+    while i + 1 < s.len() {
+        match &s[i..i + 2] {
+            b"/*" => {
+                depth += 1;
+                i += 2;
+            }
+            b"*/" => {
+                depth -= 1;
+                i += 2;
+                if depth == 0 {
+                    return i;
+                }
+            }
+            _ => i += 1,
+        }
+    }
+    s.len()
+}
+
+fn is_ident_start(b: u8) -> bool {
+    b.is_ascii_alphabetic() || b == b'_'
+}
+
+fn is_ident(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || matches!(b, b'_' | b'@' | b'#' | b'$')
+}
+
+/// Most rows SQL Server takes in one `VALUES` list.
+const MAX_VALUES_ROWS: usize = 1000;
+
+/// A statement with `$name` placeholders, parsed once so it can be bound
+/// with any set of values.
+#[derive(Debug, Clone)]
+pub(crate) struct Template<'a> {
+    sql: &'a str,
+    parts: Vec<Part<'a>>,
+    names: Vec<&'a str>,
+    /// Byte range of the row in a single-row `INSERT ... VALUES (...)`.
+    values: Option<Range<usize>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Part<'a> {
+    Sql(&'a str),
+    /// Index into the template's names.
+    Param(usize),
+}
+
+impl<'a> Template<'a> {
+    pub(crate) fn parse(sql: &'a str) -> Self {
+        let mut parts = Vec::new();
+        let mut names: Vec<&str> = Vec::new();
+        let (mut pos, mut code_start) = (0, 0);
+        for (token, s) in Tokens::new(sql) {
+            if token == Token::Placeholder {
+                if code_start < pos {
+                    parts.push(Part::Sql(&sql[code_start..pos]));
+                }
+                let name = &s[1..];
+                let idx = names.iter().position(|n| *n == name).unwrap_or_else(|| {
+                    names.push(name);
+                    names.len() - 1
+                });
+                parts.push(Part::Param(idx));
+                code_start = pos + s.len();
+            }
+            pos += s.len();
+        }
+        if code_start < pos {
+            parts.push(Part::Sql(&sql[code_start..]));
+        }
+        Self {
+            sql,
+            parts,
+            names,
+            values: values_row(sql),
+        }
+    }
+
+    /// Distinct placeholder names in order of first use.
+    pub(crate) fn names(&self) -> &[&'a str] {
+        &self.names
+    }
+
+    /// The statement with each placeholder replaced by a bind parameter,
+    /// `@P1` for the first name, numbered on from `offset`.
+    pub(crate) fn bind_sql(&self, offset: usize) -> String {
+        self.render(|idx| format!("@P{}", offset + idx + 1))
+    }
+
+    /// The statement with each placeholder replaced by `param(idx)`, `idx`
+    /// being the placeholder's index in [`Template::names`].
+    fn render(&self, param: impl Fn(usize) -> String) -> String {
+        let mut sql = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Sql(s) => sql += s,
+                Part::Param(idx) => sql += &param(*idx),
+            }
+        }
+        sql
+    }
+
+    /// Most rows [`Template::bind_rows`] can take when each row may use up
+    /// to `max_params` bind parameters.
+    pub(crate) fn max_rows(&self, max_params: usize) -> usize {
+        let rows = max_params / self.names.len().max(1);
+        match self.values {
+            Some(_) => rows.min(MAX_VALUES_ROWS),
+            None => rows,
+        }
+        .max(1)
+    }
+
+    /// The statement bound for `rows` rows of values sent in one round trip,
+    /// the placeholders of row `i` numbered on from `i * names().len()`. A
+    /// single-row `INSERT ... VALUES` becomes one insert of all the rows,
+    /// anything else is repeated once per row.
+    pub(crate) fn bind_rows(&self, rows: usize) -> String {
+        self.render_rows(rows, |idx| format!("@P{}", idx + 1))
+    }
+
+    /// Like [`Template::bind_rows`], but with the placeholders replaced by
+    /// `literals`, the values of all rows one after the other.
+    pub(crate) fn fill_rows(&self, rows: usize, literals: &[String]) -> String {
+        self.render_rows(rows, |idx| literals[idx].clone())
+    }
+
+    fn render_rows(&self, rows: usize, param: impl Fn(usize) -> String) -> String {
+        let n = self.names.len();
+        if rows == 1 {
+            return self.render(param);
+        }
+        if let Some(range) = &self.values {
+            let row = Template::parse(&self.sql[range.clone()]);
+            let values = (0..rows).map(|i| row.render(|idx| param(i * n + idx)));
+            let values = values.collect::<Vec<_>>().join(",\n    ");
+            return format!(
+                "{}{values}{}",
+                &self.sql[..range.start],
+                &self.sql[range.end..]
+            );
+        }
+        (0..rows)
+            .map(|i| terminated(&self.render(|idx| param(i * n + idx))))
+            .collect()
+    }
+}
+
+/// The statement ending in a `;` and a line break.
+pub(crate) fn terminated(statement: &str) -> String {
+    let mut sql = statement
+        .trim_end_matches(|c: char| c.is_whitespace() || c == ';')
+        .to_string();
+    // a `-- comment` would swallow the terminator
+    if let Some((Token::Comment, c)) = Tokens::new(&sql).last() {
+        if c.starts_with("--") {
+            sql.push('\n');
+        }
+    }
+    sql + ";\n"
+}
+
+/// Part of a script sent to the server on its own, the script being split
+/// on `GO` lines.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Batch<'a> {
+    pub sql: &'a str,
+    /// Times to run the batch, `GO 5` runs the batch before it five times.
+    pub repeat: u32,
+    /// Line of the script the batch starts on, counting from 1.
+    pub line: usize,
+}
+
+impl Batch<'_> {
+    /// Line of the script the batch ends on.
+    pub(crate) fn end_line(&self) -> usize {
+        self.line + self.sql.trim_end_matches('\n').matches('\n').count()
+    }
+}
+
+/// Splits a script into batches on lines holding just `GO` or `GO <count>`,
+/// optionally followed by a `--` comment. A `GO` inside a literal, identifier
+/// or comment is left alone. Batches of only whitespace are dropped.
+pub(crate) fn batches(script: &str) -> Result<Vec<Batch<'_>>> {
+    let in_code = code_mask(script);
+    let mut batches = Vec::new();
+    let (mut start, mut start_line, mut pos) = (0, 1, 0);
+    for (idx, line) in script.split_inclusive('\n').enumerate() {
+        let line_start = pos;
+        pos += line.len();
+        let repeat = go_count(line, &in_code[line_start..pos])
+            .with_context(|| format!("line {}", idx + 1))?;
+        if let Some(repeat) = repeat {
+            batches.push(Batch {
+                sql: &script[start..line_start],
+                repeat,
+                line: start_line,
+            });
+            (start, start_line) = (pos, idx + 2);
+        }
+    }
+    batches.push(Batch {
+        sql: &script[start..],
+        repeat: 1,
+        line: start_line,
+    });
+    batches.retain(|batch| !batch.sql.trim().is_empty());
+    Ok(batches)
+}
+
+/// Lines of a script, each with whether its first non-blank character is
+/// code rather than inside a literal, identifier or comment.
+pub(crate) fn code_lines(script: &str) -> Vec<(&str, bool)> {
+    let in_code = code_mask(script);
+    let mut pos = 0;
+    script
+        .split_inclusive('\n')
+        .map(|line| {
+            let first = line.find(|c: char| !c.is_whitespace());
+            let is_code = first.is_some_and(|i| in_code[pos + i]);
+            pos += line.len();
+            (line, is_code)
+        })
+        .collect()
+}
+
+/// Whether each byte of the script is code, outside literals, identifiers
+/// and comments.
+fn code_mask(script: &str) -> Vec<bool> {
+    let mut in_code = vec![false; script.len()];
+    let mut pos = 0;
+    for (token, s) in Tokens::new(script) {
+        if matches!(token, Token::Code | Token::Placeholder) {
+            in_code[pos..pos + s.len()].fill(true);
+        }
+        pos += s.len();
+    }
+    in_code
+}
+
+/// The count of a `GO` line, `None` if the line is anything else.
+fn go_count(line: &str, in_code: &[bool]) -> Result<Option<u32>> {
+    // only a `--` comment may follow the code of the line
+    let code_len = in_code.iter().position(|c| !c).unwrap_or(line.len());
+    let (code, rest) = line.split_at(code_len);
+    if !rest.is_empty() && !rest.starts_with("--") {
+        return Ok(None);
+    }
+    let mut words = code.split_whitespace();
+    match (words.next(), words.next(), words.next()) {
+        (Some(go), None, _) if go.eq_ignore_ascii_case("go") => Ok(Some(1)),
+        (Some(go), Some(count), None) if go.eq_ignore_ascii_case("go") => match count.parse() {
+            Ok(0) | Err(_) => bail!("`GO {count}` needs a positive count"),
+            Ok(count) => Ok(Some(count)),
+        },
+        _ => Ok(None),
+    }
+}
+
+/// Where `INSERT ... VALUES` is being looked for in [`values_row`].
+#[derive(Debug)]
+enum Scan {
+    Start,
+    Target,
+    Values,
+    Row { start: usize, depth: usize },
+    After(Range<usize>),
+}
+
+/// Finds the `(...)` row of a statement that is a single-row
+/// `INSERT ... VALUES (...)` with all of its placeholders inside the row.
+fn values_row(sql: &str) -> Option<Range<usize>> {
+    let mut state = Scan::Start;
+    let mut pos = 0;
+    for (token, s) in Tokens::new(sql) {
+        let offset = pos;
+        pos += s.len();
+        match (token, &state) {
+            (Token::Comment, _) => continue,
+            (Token::Placeholder, Scan::Row { .. }) => continue,
+            (Token::Literal | Token::Identifier, Scan::Target | Scan::Row { .. }) => continue,
+            (Token::Code, _) => {}
+            _ => return None,
+        }
+        let bytes = s.as_bytes();
+        let mut word_start = None;
+        for i in 0..=bytes.len() {
+            let b = bytes.get(i).copied();
+            if b.is_some_and(is_ident) {
+                word_start.get_or_insert(i);
+                continue;
+            }
+            if let Some(ws) = word_start.take() {
+                let word = &s[ws..i];
+                state = match state {
+                    Scan::Start if word.eq_ignore_ascii_case("insert") => Scan::Target,
+                    Scan::Target if word.eq_ignore_ascii_case("values") => Scan::Values,
+                    Scan::Target | Scan::Row { .. } => state,
+                    _ => return None,
+                };
+            }
+            state = match (b, state) {
+                (None, state) => state,
+                (Some(b'('), Scan::Values) => Scan::Row {
+                    start: offset + i,
+                    depth: 1,
+                },
+                (Some(b'('), Scan::Row { start, depth }) => Scan::Row {
+                    start,
+                    depth: depth + 1,
+                },
+                (Some(b')'), Scan::Row { start, depth: 1 }) => Scan::After(start..offset + i + 1),
+                (Some(b')'), Scan::Row { start, depth }) => Scan::Row {
+                    start,
+                    depth: depth - 1,
+                },
+                (Some(b), state) if b == b';' || b.is_ascii_whitespace() => state,
+                (Some(_), state @ (Scan::Target | Scan::Row { .. })) => state,
+                (Some(_), _) => return None,
+            };
+        }
+    }
+    match state {
+        Scan::After(range) => Some(range),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens() {
+        let sql = "SELECT 'a''$b' AS [x]]$y], \"$z\" -- $c\n/* /* $d */ */ $e";
+        let tokens = Tokens::new(sql).collect::<Vec<_>>();
+        assert_eq!(
+            tokens,
+            [
+                (Token::Code, "SELECT "),
+                (Token::Literal, "'a''$b'"),
+                (Token::Code, " AS "),
+                (Token::Identifier, "[x]]$y]"),
+                (Token::Code, ", "),
+                (Token::Identifier, "\"$z\""),
+                (Token::Code, " "),
+                (Token::Comment, "-- $c"),
+                (Token::Code, "\n"),
+                (Token::Comment, "/* /* $d */ */"),
+                (Token::Code, " "),
+                (Token::Placeholder, "$e"),
+            ]
+        );
+    }
+
+    #[test]
+    fn pseudo_columns() {
+        let t = Template::parse(
+            "MERGE t USING s ON t.id = s.id WHEN MATCHED THEN UPDATE SET t.v = $v \
+             OUTPUT $action, inserted.$IDENTITY, $PARTITION.pf($v);",
+        );
+        assert_eq!(t.names(), ["v"]);
+        assert_eq!(
+            t.bind_sql(0),
+            "MERGE t USING s ON t.id = s.id WHEN MATCHED THEN UPDATE SET t.v = @P1 \
+             OUTPUT $action, inserted.$IDENTITY, $PARTITION.pf(@P1);"
+        );
+        let t = Template::parse("SELECT $node_id, $from_id, $to_id FROM e WHERE $actions = 1");
+        assert_eq!(t.names(), ["actions"]);
+    }
+
+    #[test]
+    fn unterminated() {
+        let tokens = Tokens::new("SELECT 'abc").collect::<Vec<_>>();
+        assert_eq!(tokens, [(Token::Code, "SELECT "), (Token::Literal, "'abc")]);
+    }
+
+    #[test]
+    fn template() {
+        let t = Template::parse("INSERT INTO t VALUES ($id, N'$id', $name, $id, $12, a$b)");
+        assert_eq!(t.names(), ["id", "name"]);
+        assert_eq!(
+            t.bind_sql(0),
+            "INSERT INTO t VALUES (@P1, N'$id', @P2, @P1, $12, a$b)"
+        );
+        assert_eq!(
+            t.bind_sql(2),
+            "INSERT INTO t VALUES (@P3, N'$id', @P4, @P3, $12, a$b)"
+        );
+    }
+
+    #[test]
+    fn split_batches() -> Result<()> {
+        let script = "\
+CREATE TABLE t (a int)
+go
+INSERT INTO t VALUES (1) -- GO
+  GO 3 -- three times
+SELECT '
+GO
+', [
+GO
+] /*
+GO
+*/
+GO";
+        let batch = |sql, repeat, line| Batch { sql, repeat, line };
+        assert_eq!(
+            batches(script)?,
+            [
+                batch("CREATE TABLE t (a int)\n", 1, 1),
+                batch("INSERT INTO t VALUES (1) -- GO\n", 3, 3),
+                batch("SELECT '\nGO\n', [\nGO\n] /*\nGO\n*/\n", 1, 5),
+            ]
+        );
+        assert_eq!(batches("\nGO\n\nGO\n")?, []);
+        assert_eq!(batches("SELECT 1")?, [batch("SELECT 1", 1, 1)]);
+        assert!(batches("SELECT 1\nGO 0\n").is_err());
+        assert!(batches("SELECT 1\nGO x\n").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn bind_rows() {
+        let t = Template::parse("insert into [t] (a, b) values ($a, COALESCE($b, 'x'));\n");
+        assert_eq!(
+            t.bind_rows(2),
+            "insert into [t] (a, b) values (@P1, COALESCE(@P2, 'x')),\n    (@P3, COALESCE(@P4, 'x'));\n"
+        );
+        assert_eq!(t.bind_rows(1), t.bind_sql(0));
+        assert_eq!(t.max_rows(2098), 1000);
+
+        let t = Template::parse("UPDATE t SET a = $a WHERE id = $id; -- by id");
+        assert_eq!(
+            t.bind_rows(2),
+            "UPDATE t SET a = @P1 WHERE id = @P2; -- by id\n;\nUPDATE t SET a = @P3 WHERE id = @P4; -- by id\n;\n"
+        );
+        assert_eq!(t.max_rows(2098), 1049);
+
+        let literals = ["1".into(), "NULL".into(), "2".into(), "N'x'".into()];
+        assert_eq!(
+            t.fill_rows(2, &literals),
+            "UPDATE t SET a = 1 WHERE id = NULL; -- by id\n;\nUPDATE t SET a = 2 WHERE id = N'x'; -- by id\n;\n"
+        );
+        assert_eq!(terminated("SELECT 1;;  \n"), "SELECT 1;\n");
+
+        for sql in [
+            "INSERT INTO t VALUES ($a), ($b)",
+            "INSERT INTO t VALUES ($a); SELECT 1",
+            "INSERT INTO t OUTPUT $a VALUES ($b)",
+            "INSERT INTO t SELECT $a",
+        ] {
+            assert_eq!(values_row(sql), None, "{sql}");
+        }
+    }
+}
@@ -0,0 +1,290 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+
+use crate::{cli::OnError, sql};
+
+type Result<T> = std::result::Result<T, anyhow::Error>;
+
+/// A script with its sqlcmd directives resolved, knowing which file and line
+/// each of its lines came from.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub(crate) struct Script {
+    pub text: String,
+    /// Runs of lines taken in order from one file: the line of `text` the
+    /// run starts on, the file it is from (`None` for the script itself) and
+    /// the line of that file it starts at.
+    origins: Vec<(usize, Option<String>, usize)>,
+}
+
+impl Script {
+    /// Where line `line` of the text came from, `line 3` of the script
+    /// itself or ``line 3 of `tables/users.sql` `` of an included file.
+    pub(crate) fn locate(&self, line: usize) -> String {
+        match self.origins.iter().rev().find(|(at, ..)| *at <= line) {
+            Some((at, Some(file), from)) => format!("line {} of `{file}`", from + line - at),
+            Some((at, None, from)) => format!("line {}", from + line - at),
+            None => format!("line {line}"),
+        }
+    }
+}
+
+/// Resolves the sqlcmd directives that read files or change the script's
+/// text: `:setvar`, `$(var)` and `:r` includes. Included files are found
+/// relative to the file including them, `dir` for the script itself.
+/// `:setvar` lines are left blank and `:on error` lines are kept for
+/// [`ErrorModes::take`], so lines outside includes keep their numbers.
+pub(crate) fn expand(script: &str, dir: &Path) -> Result<Script> {
+    let mut expander = Expander::default();
+    expander.expand(script, dir, None)?;
+    Ok(expander.script)
+}
+
+#[derive(Default)]
+struct Expander {
+    vars: HashMap<String, String>,
+    /// Files being included, to catch a file including itself.
+    including: Vec<PathBuf>,
+    script: Script,
+    /// Lines ended in the text so far, counted up to `counted` bytes.
+    lines: usize,
+    counted: usize,
+}
+
+impl Expander {
+    fn expand(&mut self, script: &str, dir: &Path, file: Option<&str>) -> Result<()> {
+        for (idx, (line, is_code)) in sql::code_lines(script).into_iter().enumerate() {
+            self.origin(file, idx + 1);
+            self.line(line, is_code, dir)
+                .with_context(|| format!("line {}", idx + 1))?;
+        }
+        Ok(())
+    }
+
+    /// Notes that the next line of text is line `line` of `file`.
+    fn origin(&mut self, file: Option<&str>, line: usize) {
+        let text = &self.script.text;
+        self.lines += text[self.counted..].matches('\n').count();
+        self.counted = text.len();
+        let at = self.lines + 1;
+        let origins = &mut self.script.origins;
+        match origins.last() {
+            Some((last_at, last_file, from))
+                if last_file.as_deref() == file && from + (at - last_at) == line =>
+            {
+                return;
+            }
+            // a `:r` line is replaced by what it includes
+            Some((last_at, ..)) if *last_at == at => {
+                origins.pop();
+            }
+            _ => {}
+        }
+        origins.push((at, file.map(str::to_string), line));
+    }
+
+    fn line(&mut self, line: &str, is_code: bool, dir: &Path) -> Result<()> {
+        // like sqlcmd, variables are replaced everywhere, literals included
+        let line = self.substitute(line)?;
+        let Some((command, arg)) = command(&line).filter(|_| is_code) else {
+            self.script.text += &line;
+            return Ok(());
+        };
+        match command.to_ascii_lowercase().as_str() {
+            "setvar" => {
+                let (name, value) = arg.split_once(char::is_whitespace).unwrap_or((arg, ""));
+                match value.trim() {
+                    "" => self.vars.remove(name),
+                    value => self
+                        .vars
+                        .insert(name.to_string(), unquote(value).to_string()),
+                };
+                // left blank, as `:on error` lines are, so line numbers still match
+                self.script.text.push('\n');
+            }
+            "r" => self.include(&dir.join(unquote(arg)))?,
+            "on" => {
+                parse_on_error(arg)?;
+                self.script.text += &line;
+            }
+            _ => bail!("unsupported sqlcmd command `:{command}`"),
+        }
+        Ok(())
+    }
+
+    fn include(&mut self, path: &Path) -> Result<()> {
+        let canonical = path
+            .canonicalize()
+            .with_context(|| format!("including `{}`", path.display()))?;
+        if self.including.contains(&canonical) {
+            bail!("`{}` includes itself", path.display());
+        }
+        let script = std::fs::read_to_string(path)
+            .with_context(|| format!("including `{}`", path.display()))?;
+        let dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+        let file = path.display().to_string();
+        self.including.push(canonical);
+        let res = self.expand(&script, &dir, Some(&file));
+        self.including.pop();
+        res.with_context(|| format!("in `{file}`"))?;
+        let text = &mut self.script.text;
+        if !text.is_empty() && !text.ends_with('\n') {
+            text.push('\n');
+        }
+        Ok(())
+    }
+
+    /// Replaces `$(name)` with the variable's value, or else the environment
+    /// variable of that name.
+    fn substitute(&self, line: &str) -> Result<String> {
+        let mut out = String::new();
+        let mut rest = line;
+        while let Some(start) = rest.find("$(") {
+            let Some(len) = rest[start + 2..].find(')') else {
+                break;
+            };
+            let name = &rest[start + 2..start + 2 + len];
+            let value = match self.vars.get(name) {
+                Some(value) => value.clone(),
+                None => std::env::var(name)
+                    .with_context(|| format!("variable `$({name})` is not defined"))?,
+            };
+            out += &rest[..start];
+            out += &value;
+            rest = &rest[start + 3 + len..];
+        }
+        Ok(out + rest)
+    }
+}
+
+/// Splits `:name arg` into the command's name and argument.
+fn command(line: &str) -> Option<(&str, &str)> {
+    let line = line.trim().strip_prefix(':')?;
+    let (name, arg) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    Some((name, arg.trim()))
+}
+
+fn unquote(s: &str) -> &str {
+    s.strip_prefix('"')
+        .and_then(|s| s.strip_suffix('"'))
+        .unwrap_or(s)
+}
+
+fn parse_on_error(arg: &str) -> Result<OnError> {
+    let mut words = arg.split_whitespace().map(str::to_ascii_lowercase);
+    match (
+        words.next().as_deref(),
+        words.next().as_deref(),
+        words.next(),
+    ) {
+        (Some("error"), Some("exit"), None) => Ok(OnError::Stop),
+        (Some("error"), Some("ignore"), None) => Ok(OnError::Continue),
+        _ => bail!("expected `:on error exit` or `:on error ignore`"),
+    }
+}
+
+/// The `:on error` settings of a script, by the line they are on.
+#[derive(Debug, Default)]
+pub(crate) struct ErrorModes(Vec<(usize, OnError)>);
+
+impl ErrorModes {
+    /// Takes the `:on error` lines out of a script, leaving them blank so
+    /// line numbers still match.
+    pub(crate) fn take(script: &str) -> Result<(String, Self)> {
+        let mut out = String::new();
+        let mut modes = Vec::new();
+        for (idx, (line, is_code)) in sql::code_lines(script).into_iter().enumerate() {
+            match command(line).filter(|_| is_code) {
+                Some((name, arg)) if name.eq_ignore_ascii_case("on") => {
+                    let mode = parse_on_error(arg).with_context(|| format!("line {}", idx + 1))?;
+                    modes.push((idx + 1, mode));
+                    out.push('\n');
+                }
+                _ => out += line,
+            }
+        }
+        Ok((out, Self(modes)))
+    }
+
+    /// The setting for a batch ending on `line`, `default` before the first
+    /// `:on error`.
+    pub(crate) fn at(&self, line: usize, default: OnError) -> OnError {
+        self.0
+            .iter()
+            .rev()
+            .find(|(at, _)| *at <= line)
+            .map_or(default, |(_, mode)| *mode)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expand_directives() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        std::fs::create_dir(dir.path().join("tables"))?;
+        std::fs::write(
+            dir.path().join("tables/users.sql"),
+            "CREATE TABLE $(schema).users (id int)\n:r roles.sql",
+        )?;
+        std::fs::write(
+            dir.path().join("tables/roles.sql"),
+            "CREATE TABLE $(schema).roles (id int)",
+        )?;
+        let script = "\
+:setvar schema \"app\"
+:r tables/users.sql
+/*
+:r nothing.sql
+*/
+SELECT '$(schema)'
+:on error ignore
+";
+        let expanded = expand(script, dir.path())?;
+        assert_eq!(
+            expanded.text,
+            "\n\
+CREATE TABLE app.users (id int)
+CREATE TABLE app.roles (id int)
+/*
+:r nothing.sql
+*/
+SELECT 'app'
+:on error ignore
+"
+        );
+
+        let users = dir.path().join("tables/users.sql");
+        let roles = dir.path().join("tables/roles.sql");
+        assert_eq!(expanded.locate(1), "line 1");
+        let of = |line, path: &Path| format!("line {line} of `{}`", path.display());
+        assert_eq!(expanded.locate(2), of(1, &users));
+        assert_eq!(expanded.locate(3), of(1, &roles));
+        assert_eq!(expanded.locate(4), "line 3");
+        assert_eq!(expanded.locate(7), "line 6");
+
+        assert!(expand("SELECT $(nope_not_defined)", dir.path()).is_err());
+        assert!(expand(":connect server", dir.path()).is_err());
+        assert!(expand(":on error maybe", dir.path()).is_err());
+        std::fs::write(dir.path().join("loop.sql"), ":r loop.sql")?;
+        assert!(expand(":r loop.sql", dir.path()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn error_modes() -> Result<()> {
+        let (script, modes) = ErrorModes::take("SELECT 1\n:On Error ignore\nGO\n:on error exit\n")?;
+        assert_eq!(script, "SELECT 1\n\nGO\n\n");
+        assert_eq!(modes.at(1, OnError::Stop), OnError::Stop);
+        assert_eq!(modes.at(1, OnError::Continue), OnError::Continue);
+        assert_eq!(modes.at(2, OnError::Stop), OnError::Continue);
+        assert_eq!(modes.at(4, OnError::Continue), OnError::Stop);
+        Ok(())
+    }
+}